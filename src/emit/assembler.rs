@@ -0,0 +1,139 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{HassiumError, Span};
+use crate::runtime::vm::{Capture, CodeObj, ExceptionHandler, VMInstruction};
+
+/// A code object that is still being emitted. Jumps hold label ids until
+/// `assemble` swaps them for instruction offsets
+pub struct CodeBuilder {
+    pub instructions: Vec<VMInstruction>,
+    pub labels: Vec<(u32, u32)>,
+    pub handlers: Vec<ExceptionHandler>,
+    /// The offset of the first instruction emitted for each span, in order
+    pub spans: Vec<(u32, Span)>,
+    /// Open block scopes, mapping names to local slots. A module's outermost
+    /// block has no entry here since names declared there are globals
+    pub scopes: Vec<HashMap<String, u32>>,
+    pub local_names: Vec<String>,
+    /// Local slots that nested functions refer to, these live in cells
+    pub captured: HashSet<u32>,
+    /// Where each upvalue comes from in the enclosing code object
+    pub upvalues: Vec<Capture>,
+    pub is_module: bool,
+    pub is_class: bool,
+}
+
+impl CodeBuilder {
+    pub fn new() -> CodeBuilder {
+        CodeBuilder {
+            instructions: Vec::new(),
+            labels: Vec::new(),
+            handlers: Vec::new(),
+            spans: Vec::new(),
+            scopes: vec![HashMap::new()],
+            local_names: Vec::new(),
+            captured: HashSet::new(),
+            upvalues: Vec::new(),
+            is_module: false,
+            is_class: false,
+        }
+    }
+
+    pub fn new_class() -> CodeBuilder {
+        CodeBuilder {
+            is_class: true,
+            ..CodeBuilder::new()
+        }
+    }
+
+    pub fn new_module() -> CodeBuilder {
+        CodeBuilder {
+            scopes: Vec::new(),
+            is_module: true,
+            ..CodeBuilder::new()
+        }
+    }
+}
+
+/// Resolves every jump to the offset its label was placed at. Labels that were
+/// never placed, or placed twice, are emitter bugs and rejected here rather
+/// than being left for the VM to jump somewhere arbitrary. Accesses to captured
+/// slots become cell accesses, which can only be known once the whole code
+/// object and the functions nested in it are emitted
+pub fn assemble(builder: CodeBuilder) -> Result<CodeObj, HassiumError> {
+    let mut offsets: HashMap<u32, u32> = HashMap::new();
+    for (label, offset) in builder.labels {
+        if offsets.insert(label, offset).is_some() {
+            return Err(assembly_error(format!(
+                "Label {} is placed more than once!",
+                label
+            )));
+        }
+    }
+    let resolve = |label: u32| -> Result<u32, HassiumError> {
+        offsets
+            .get(&label)
+            .copied()
+            .ok_or_else(|| assembly_error(format!("Label {} is never placed!", label)))
+    };
+
+    let mut instructions: Vec<VMInstruction> = Vec::with_capacity(builder.instructions.len());
+    for inst in builder.instructions {
+        instructions.push(match inst {
+            VMInstruction::IterNext { jump_if_done } => VMInstruction::IterNext {
+                jump_if_done: resolve(jump_if_done)?,
+            },
+            VMInstruction::Jump { to } => VMInstruction::Jump { to: resolve(to)? },
+            VMInstruction::JumpIfFalse { to } => VMInstruction::JumpIfFalse { to: resolve(to)? },
+            VMInstruction::LoadLocal { slot } if builder.captured.contains(&slot) => {
+                VMInstruction::LoadCell { slot }
+            }
+            VMInstruction::StoreLocal { slot } if builder.captured.contains(&slot) => {
+                VMInstruction::StoreCell { slot }
+            }
+            inst => inst,
+        });
+    }
+    let mut cell_slots: Vec<u32> = builder.captured.into_iter().collect();
+    cell_slots.sort();
+    Ok(CodeObj::new(
+        instructions,
+        builder.handlers,
+        builder.spans,
+        builder.local_names,
+        cell_slots,
+    ))
+}
+
+fn assembly_error(message: String) -> HassiumError {
+    HassiumError::Syntax {
+        message,
+        location: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jumps_to_labels_never_placed_are_rejected() {
+        let mut builder = CodeBuilder::new();
+        builder.labels.push((1, 0));
+        builder.instructions.push(VMInstruction::Jump { to: 1 });
+        builder
+            .instructions
+            .push(VMInstruction::JumpIfFalse { to: 2 });
+        let error = assemble(builder).expect_err("expected an assembly error");
+        assert_eq!(error.to_string(), "SyntaxError: Label 2 is never placed!");
+
+        let mut builder = CodeBuilder::new();
+        builder.labels.push((1, 0));
+        builder.labels.push((1, 1));
+        let error = assemble(builder).expect_err("expected an assembly error");
+        assert_eq!(
+            error.to_string(),
+            "SyntaxError: Label 1 is placed more than once!"
+        );
+    }
+}
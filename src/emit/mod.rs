@@ -1,166 +1,587 @@
-use std::collections::VecDeque;
+mod assembler;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
 
 use crate::{
-    parser::{AstNode, BinOpType, FuncParams, UnaryOpType},
-    runtime::vm::{CodeObj, VMInstruction},
+    error::{HassiumError, Span},
+    parser::{AstKind, AstNode, BinOpType, FuncParams, InterpolationPart, UnaryOpType},
+    runtime::vm::{Capture, CodeObj, ExceptionHandler, VMInstruction},
 };
 
+use self::assembler::{assemble, CodeBuilder};
+
+/// Names that load a fixed value instead of a variable
+const KEYWORDS: [&str; 4] = ["false", "null", "self", "true"];
+
+/// The jump targets of a loop that `break` and `continue` can refer to
+struct LoopLabels {
+    name: Option<String>,
+    continue_label: u32,
+    break_label: u32,
+    /// How many try statements were open when the loop started
+    tries: usize,
+}
+
+/// A try statement being emitted. Jumping out of it runs its finally block
+/// first, the copies of finally blocks made for such jumps are cut out of the
+/// ranges it and the try statements inside it handle
+struct TryBlock {
+    finally_body: Option<AstNode>,
+    /// How many loops were open when the try statement started
+    loops: usize,
+    gaps: Vec<(u32, u32)>,
+}
+
 pub struct EmitContext {
-    code_obj_stack: VecDeque<CodeObj>,
+    code_obj_stack: VecDeque<CodeBuilder>,
+    /// Names the VM defines for every module, assigning to one outside the top
+    /// level of the module shadows it with a local
+    builtins: HashSet<String>,
+    /// Module level names, anything else has to be a local or a built-in
+    globals: HashSet<String>,
+    loops: Vec<LoopLabels>,
+    tries: Vec<TryBlock>,
     label_index: u32,
     tmp_index: u32,
+    /// Where the node being visited starts, instructions are located at it
+    span: Span,
 }
 
 impl EmitContext {
     fn add_inst(&mut self, inst: VMInstruction) {
-        let code_obj: &mut CodeObj = self
-            .code_obj_stack
-            .get_mut(self.code_obj_stack.len() - 1)
-            .unwrap();
+        let code_obj: &mut CodeBuilder = self.code_obj_stack.front_mut().unwrap();
+        if code_obj.spans.last().map(|(_, span)| span) != Some(&self.span) {
+            let offset = code_obj.instructions.len() as u32;
+            code_obj.spans.push((offset, self.span.clone()));
+        }
         code_obj.instructions.push(inst);
     }
 
+    fn current_offset(&self) -> u32 {
+        self.code_obj_stack.front().unwrap().instructions.len() as u32
+    }
+
+    fn add_handler(&mut self, handler: ExceptionHandler) {
+        let code_obj: &mut CodeBuilder = self.code_obj_stack.front_mut().unwrap();
+        code_obj.handlers.push(handler);
+    }
+
+    /// Adds a handler for `start..end` leaving out the `gaps` in that range
+    fn add_handler_around(
+        &mut self,
+        start: u32,
+        end: u32,
+        gaps: &[(u32, u32)],
+        target: u32,
+        finally: bool,
+    ) {
+        let mut gaps = gaps.to_vec();
+        gaps.sort();
+        let mut start = start;
+        for (gap_start, gap_end) in gaps {
+            if start < gap_start && gap_start < end {
+                self.add_handler(ExceptionHandler {
+                    start,
+                    end: gap_start,
+                    target,
+                    finally,
+                });
+            }
+            start = start.max(gap_end);
+        }
+        if start < end {
+            self.add_handler(ExceptionHandler {
+                start,
+                end,
+                target,
+                finally,
+            });
+        }
+    }
+
+    /// Emits the finally blocks of the try statements from `depth` on, innermost
+    /// first, for a jump out of them
+    fn exit_tries(&mut self, depth: usize) -> Result<(), HassiumError> {
+        for index in (depth..self.tries.len()).rev() {
+            let finally_body = match &self.tries[index].finally_body {
+                Some(finally_body) => finally_body.clone(),
+                None => continue,
+            };
+            // The copy is emitted as if it were where the finally block is
+            let tries = self.tries.split_off(index);
+            let loops = self.loops.split_off(tries[0].loops);
+            let start = self.current_offset();
+            let ret = visit(self, finally_body);
+            let end = self.current_offset();
+            self.loops.extend(loops);
+            self.tries.extend(tries);
+            ret?;
+            for try_block in &mut self.tries[index..] {
+                try_block.gaps.push((start, end));
+            }
+        }
+        Ok(())
+    }
+
     fn create_label(&mut self) -> u32 {
         self.label_index += 1;
-        return self.label_index;
+        self.label_index
     }
 
+    /// Points `label` at the next instruction to be emitted
     fn place_label(&mut self, label: u32) {
-        let code_obj: &mut CodeObj = self
-            .code_obj_stack
-            .get_mut(self.code_obj_stack.len() - 1)
-            .unwrap();
-        code_obj
-            .labels
-            .insert(label, code_obj.instructions.len() as u32 - 1);
+        let offset = self.current_offset();
+        let code_obj: &mut CodeBuilder = self.code_obj_stack.front_mut().unwrap();
+        code_obj.labels.push((label, offset));
+    }
+
+    /// Assembles the innermost code object, also returning what it captures from
+    /// the code object it is nested in
+    fn finish_code_obj(&mut self) -> Result<(CodeObj, Vec<Capture>), HassiumError> {
+        let mut code_obj = self.code_obj_stack.pop_front().unwrap();
+        let captures = std::mem::take(&mut code_obj.upvalues);
+        Ok((assemble(code_obj)?, captures))
+    }
+
+    /// Finds the innermost loop, or the loop called `name` if one was given
+    fn find_loop(
+        &self,
+        name: &Option<String>,
+        statement: &str,
+    ) -> Result<&LoopLabels, HassiumError> {
+        let found = match name {
+            Some(name) => self
+                .loops
+                .iter()
+                .rev()
+                .find(|labels| labels.name.as_ref() == Some(name)),
+            None => self.loops.last(),
+        };
+        found.ok_or_else(|| HassiumError::Syntax {
+            message: match name {
+                Some(name) => format!("{} refers to unknown loop {}!", statement, name),
+                None => format!("{} used outside of a loop!", statement),
+            },
+            location: None,
+        })
     }
 
+    /// Temporaries are named so that they can never clash with an identifier
     fn tmp_symbol(&mut self) -> String {
         self.tmp_index += 1;
-        return format!("_{}", self.tmp_index);
+        format!("<tmp {}>", self.tmp_index)
+    }
+
+    fn push_scope(&mut self) {
+        let code_obj: &mut CodeBuilder = self.code_obj_stack.front_mut().unwrap();
+        code_obj.scopes.push(HashMap::new());
+    }
+
+    fn pop_scope(&mut self) {
+        let code_obj: &mut CodeBuilder = self.code_obj_stack.front_mut().unwrap();
+        code_obj.scopes.pop();
+    }
+
+    /// The slot the next local declared will get
+    fn next_slot(&self) -> u32 {
+        self.code_obj_stack.front().unwrap().local_names.len() as u32
+    }
+
+    /// Starts each iteration of a loop with new cells for the locals it declares
+    /// from slot `from` on. Which those are is only known after the body, so
+    /// `end_fresh_cells` fills in the end of the range
+    fn begin_fresh_cells(&mut self, from: u32) -> usize {
+        self.add_inst(VMInstruction::FreshCells { from, to: from });
+        self.current_offset() as usize - 1
+    }
+
+    fn end_fresh_cells(&mut self, offset: usize) {
+        let to = self.next_slot();
+        let code_obj: &mut CodeBuilder = self.code_obj_stack.front_mut().unwrap();
+        if let VMInstruction::FreshCells { to: end, .. } = &mut code_obj.instructions[offset] {
+            *end = to;
+        }
+    }
+
+    /// Gives `name` a new slot in the innermost scope, shadowing any outer one
+    fn declare_local(&mut self, name: &str) -> u32 {
+        let code_obj: &mut CodeBuilder = self.code_obj_stack.front_mut().unwrap();
+        let slot = code_obj.local_names.len() as u32;
+        code_obj.local_names.push(name.to_string());
+        code_obj
+            .scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), slot);
+        slot
+    }
+
+    fn find_local(&self, name: &str) -> Option<u32> {
+        self.find_local_at(0, name)
+    }
+
+    /// `depth` counts code objects outwards from the one being emitted
+    fn find_local_at(&self, depth: usize, name: &str) -> Option<u32> {
+        let code_obj: &CodeBuilder = &self.code_obj_stack[depth];
+        code_obj
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    /// Finds `name` in an enclosing function and threads it through every code
+    /// object in between as an upvalue, returning its index at `depth`. Class
+    /// bodies are skipped over since their locals become class members
+    fn find_upvalue(&mut self, depth: usize, name: &str) -> Option<u32> {
+        let parent = depth + 1;
+        if parent >= self.code_obj_stack.len() {
+            return None;
+        }
+        let capture = match self.find_local_at(parent, name) {
+            Some(slot) if !self.code_obj_stack[parent].is_class => {
+                self.code_obj_stack[parent].captured.insert(slot);
+                Capture::Local(slot)
+            }
+            _ => Capture::Upvalue(self.find_upvalue(parent, name)?),
+        };
+        let upvalues = &mut self.code_obj_stack[depth].upvalues;
+        let index = match upvalues.iter().position(|existing| *existing == capture) {
+            Some(index) => index,
+            None => {
+                upvalues.push(capture);
+                upvalues.len() - 1
+            }
+        };
+        Some(index as u32)
+    }
+
+    fn load_name(&mut self, name: &str) -> Result<(), HassiumError> {
+        if let Some(slot) = self.find_local(name) {
+            self.add_inst(VMInstruction::LoadLocal { slot });
+        } else if let Some(index) = self.find_upvalue(0, name) {
+            self.add_inst(VMInstruction::LoadUpvalue { index });
+        } else if self.globals.contains(name) || self.builtins.contains(name) {
+            self.add_inst(VMInstruction::LoadGlobal {
+                name: name.to_string(),
+            });
+        } else {
+            return Err(HassiumError::name_error(format!(
+                "{} is not defined!",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    fn store_name(&mut self, name: &str) {
+        let inst = self.resolve_store(name);
+        self.add_inst(inst);
+    }
+
+    /// Assigning to a name that is not visible yet declares it, as a global at
+    /// the top level of a module and as a local in the innermost block otherwise.
+    /// Functions assign to the globals that already exist rather than shadowing
+    /// them, class bodies always declare members
+    fn resolve_store(&mut self, name: &str) -> VMInstruction {
+        if let Some(slot) = self.find_local(name) {
+            return VMInstruction::StoreLocal { slot };
+        }
+        let code_obj: &CodeBuilder = self.code_obj_stack.front().unwrap();
+        let is_class = code_obj.is_class;
+        if code_obj.is_module && code_obj.scopes.is_empty() {
+            self.globals.insert(name.to_string());
+            return VMInstruction::StoreGlobal {
+                name: name.to_string(),
+            };
+        }
+        if !code_obj.is_module {
+            if let Some(index) = self.find_upvalue(0, name) {
+                return VMInstruction::StoreUpvalue { index };
+            }
+        }
+        if self.globals.contains(name) && !is_class {
+            return VMInstruction::StoreGlobal {
+                name: name.to_string(),
+            };
+        }
+        VMInstruction::StoreLocal {
+            slot: self.declare_local(name),
+        }
     }
 }
 
-pub fn build_module(ast: AstNode) -> CodeObj {
+/// Compiles a module. `builtins` are the names the VM it will run in defines and
+/// `globals` those the module already has, using any other undefined name is an
+/// error
+pub fn build_module(
+    ast: AstNode,
+    builtins: Vec<String>,
+    globals: Vec<String>,
+) -> Result<CodeObj, HassiumError> {
     let mut context: EmitContext = EmitContext {
         code_obj_stack: VecDeque::new(),
+        builtins: builtins.into_iter().collect(),
+        globals: globals.into_iter().collect(),
+        loops: Vec::new(),
+        tries: Vec::new(),
         label_index: 0,
         tmp_index: 0,
+        span: ast.span.clone(),
     };
-    context.code_obj_stack.push_front(CodeObj::new(false));
-    visit(&mut context, ast);
-    return context.code_obj_stack.pop_front().unwrap();
+    context.code_obj_stack.push_front(CodeBuilder::new_module());
+    let children = match ast.kind {
+        AstKind::Block { children } => children,
+        kind => vec![kind.at(ast.span)],
+    };
+    // Module level names are collected up front so that functions can refer to
+    // ones that are only assigned further down
+    for child in &children {
+        declare_global(&mut context, child);
+    }
+    for child in children {
+        visit(&mut context, child)?;
+    }
+    Ok(context.finish_code_obj()?.0)
+}
+
+fn declare_global(context: &mut EmitContext, node: &AstNode) {
+    match &node.kind {
+        AstKind::Class { name, .. } | AstKind::Func { name, .. } => {
+            context.globals.insert(name.clone());
+        }
+        AstKind::ExpressionStatement { expression } => declare_global(context, expression),
+        AstKind::Import { path } => {
+            context.globals.insert(path.last().unwrap().clone());
+        }
+        AstKind::Assign { left, right } => {
+            context.globals.extend(pattern_names(left));
+            declare_global(context, right);
+        }
+        _ => (),
+    }
 }
 
-fn visit(context: &mut EmitContext, node: AstNode) {
-    let node_clone = node.clone();
-    match node {
-        AstNode::Block { children } => visit_block(context, *children),
-        AstNode::Break => visit_break(context),
-        AstNode::Class {
+/// The names an assignment to `pattern` binds, nested tuples included
+fn pattern_names(pattern: &AstNode) -> Vec<String> {
+    match &pattern.kind {
+        AstKind::Id { value } => vec![value.clone()],
+        AstKind::Tuple { items } => items.iter().flat_map(pattern_names).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Errors that no nested node located are located at `node`
+fn visit(context: &mut EmitContext, node: AstNode) -> Result<(), HassiumError> {
+    let span = std::mem::replace(&mut context.span, node.span);
+    let ret = match node.kind {
+        AstKind::Block { children } => visit_block(context, children),
+        AstKind::Break { label } => visit_break(context, label),
+        AstKind::Class {
             name,
             extends,
             body,
         } => visit_class(context, name, *extends, *body),
-        AstNode::Continue => visit_continue(context),
-        AstNode::Empty => (),
-        AstNode::For {
+        AstKind::Continue { label } => visit_continue(context, label),
+        AstKind::Empty => Ok(()),
+        AstKind::For {
+            label,
             initial,
             condition,
             repeated,
             body,
-        } => visit_for(context, *initial, *condition, *repeated, *body),
-        AstNode::Foreach { var, target, body } => visit_foreach(context, var, *target, *body),
-        AstNode::Func {
+        } => visit_for(context, label, *initial, *condition, *repeated, *body),
+        AstKind::Foreach {
+            label,
+            var,
+            target,
+            body,
+        } => visit_foreach(context, label, *var, *target, *body),
+        AstKind::Func {
             name,
             params,
             return_type,
             body,
         } => visit_func(context, name, params, *return_type, *body),
-        AstNode::If {
+        AstKind::If {
             predicate,
             body,
             else_body,
         } => visit_if(context, *predicate, *body, *else_body),
-        AstNode::Import { target } => visit_import(context, *target),
-        AstNode::Raise { value } => visit_raise(context, *value),
-        AstNode::Return { value } => visit_return(context, *value),
-        AstNode::Super { args } => visit_super(context, *args),
-        AstNode::TryCatch {
-            try_body: _,
-            value: _,
-            catch_body: _,
-        } => visit_try_catch(context, node_clone),
-        AstNode::While { condition, body } => visit_while(context, *condition, *body),
-        AstNode::ExpressionStatement { expression } => {
+        AstKind::Import { path } => visit_import(context, path),
+        AstKind::Raise { value } => visit_raise(context, *value),
+        AstKind::Return { value } => visit_return(context, *value),
+        AstKind::Super { args } => visit_super(context, args),
+        AstKind::TryCatch {
+            try_body,
+            value,
+            catch_body,
+            finally_body,
+        } => visit_try_catch(
+            context,
+            *try_body,
+            value,
+            catch_body.map(|body| *body),
+            finally_body.map(|body| *body),
+        ),
+        AstKind::While {
+            label,
+            condition,
+            body,
+        } => visit_while(context, label, *condition, *body),
+        AstKind::ExpressionStatement { expression } => {
             visit_expression_statement(context, *expression)
         }
-        AstNode::Assign { left, right } => visit_assign(context, *left, *right),
-        AstNode::AttribAccess { target, attrib } => visit_attrib_access(context, *target, attrib),
-        AstNode::BinOp { op, left, right } => visit_bin_op(context, op, *left, *right),
-        AstNode::Id { value } => visit_id(context, value),
-        AstNode::Invoke { target, args } => visit_invoke(context, *target, *args),
-        AstNode::Number { value } => visit_number(context, value),
-        AstNode::String { value } => visit_string(context, value),
-        AstNode::Subscript { target, key } => visit_subscript(context, *key, *target),
-        AstNode::UnaryOp { op, target } => visit_unary_op(context, op, *target),
-    }
+        AstKind::Assign { left, right } => visit_assign(context, *left, *right),
+        AstKind::AttribAccess { target, attrib } => visit_attrib_access(context, *target, attrib),
+        AstKind::BinOp { op, left, right } => visit_bin_op(context, op, *left, *right),
+        AstKind::Id { value } => visit_id(context, value),
+        AstKind::Interpolation { parts } => visit_interpolation(context, parts),
+        AstKind::Dict { entries } => visit_dict(context, entries),
+        AstKind::Invoke { target, args } => visit_invoke(context, *target, args),
+        AstKind::List { items } => visit_list(context, items),
+        AstKind::Number { value } => visit_number(context, value),
+        AstKind::String { value } => visit_string(context, value),
+        AstKind::Subscript { target, key } => visit_subscript(context, *key, *target),
+        AstKind::Tuple { items } => visit_tuple(context, items),
+        AstKind::UnaryOp { op, target } => visit_unary_op(context, op, *target),
+    };
+    let span = std::mem::replace(&mut context.span, span);
+    ret.map_err(|error| error.with_location(Some(span)))
 }
 
-fn visit_block(context: &mut EmitContext, children: Vec<AstNode>) {
+fn visit_block(context: &mut EmitContext, children: Vec<AstNode>) -> Result<(), HassiumError> {
+    context.push_scope();
     for child in children {
-        visit(context, child)
+        visit(context, child)?;
     }
+    context.pop_scope();
+    Ok(())
+}
+fn visit_break(context: &mut EmitContext, label: Option<String>) -> Result<(), HassiumError> {
+    let labels = context.find_loop(&label, "break")?;
+    let (to, tries) = (labels.break_label, labels.tries);
+    context.exit_tries(tries)?;
+    context.add_inst(VMInstruction::Jump { to });
+    Ok(())
 }
-fn visit_break(context: &mut EmitContext) {}
-fn visit_class(context: &mut EmitContext, name: String, extends: Option<AstNode>, body: AstNode) {
-    context.code_obj_stack.push_front(CodeObj::new(true));
-    visit(context, body);
-    let code_obj = context.code_obj_stack.pop_front().unwrap();
+fn visit_class(
+    context: &mut EmitContext,
+    name: String,
+    extends: Option<AstNode>,
+    body: AstNode,
+) -> Result<(), HassiumError> {
+    context.code_obj_stack.push_front(CodeBuilder::new_class());
+    let loops = std::mem::take(&mut context.loops);
+    let tries = std::mem::take(&mut context.tries);
+    visit(context, body)?;
+    context.loops = loops;
+    context.tries = tries;
+    let (code_obj, captures) = context.finish_code_obj()?;
     let does_extend: bool = extends.is_some();
     if does_extend {
-        visit(context, extends.unwrap())
+        visit(context, extends.unwrap())?;
     }
     context.add_inst(VMInstruction::BuildClass {
-        name,
-        code_obj,
+        name: name.clone(),
+        code_obj: Rc::new(code_obj),
+        captures,
         does_extend,
-    })
+    });
+    context.store_name(&name);
+    context.add_inst(VMInstruction::Pop);
+    Ok(())
+}
+fn visit_continue(context: &mut EmitContext, label: Option<String>) -> Result<(), HassiumError> {
+    let labels = context.find_loop(&label, "continue")?;
+    let (to, tries) = (labels.continue_label, labels.tries);
+    context.exit_tries(tries)?;
+    context.add_inst(VMInstruction::Jump { to });
+    Ok(())
+}
+/// Visits a loop body with its labels pushed so `break` and `continue` can find them
+fn visit_loop_body(
+    context: &mut EmitContext,
+    name: Option<String>,
+    continue_label: u32,
+    break_label: u32,
+    body: AstNode,
+) -> Result<(), HassiumError> {
+    let tries = context.tries.len();
+    context.loops.push(LoopLabels {
+        name,
+        continue_label,
+        break_label,
+        tries,
+    });
+    let ret = visit(context, body);
+    context.loops.pop();
+    ret
 }
-fn visit_continue(context: &mut EmitContext) {}
 fn visit_for(
     context: &mut EmitContext,
+    label: Option<String>,
     initial: AstNode,
     condition: AstNode,
     repeated: AstNode,
     body: AstNode,
-) {
+) -> Result<(), HassiumError> {
     let end_label = context.create_label();
     let body_label = context.create_label();
-    visit(context, initial);
+    let repeat_label = context.create_label();
+    context.push_scope();
+    visit(context, initial)?;
+    context.add_inst(VMInstruction::Pop);
     context.place_label(body_label);
-    visit(context, condition);
+    visit(context, condition)?;
     context.add_inst(VMInstruction::JumpIfFalse { to: end_label });
-    visit(context, body);
-    visit(context, repeated);
+    let fresh_cells = context.begin_fresh_cells(context.next_slot());
+    visit_loop_body(context, label, repeat_label, end_label, body)?;
+    context.end_fresh_cells(fresh_cells);
+    context.place_label(repeat_label);
+    visit(context, repeated)?;
+    context.add_inst(VMInstruction::Pop);
     context.add_inst(VMInstruction::Jump { to: body_label });
     context.place_label(end_label);
+    context.pop_scope();
+    Ok(())
 }
-fn visit_foreach(context: &mut EmitContext, var: String, target: AstNode, body: AstNode) {
+fn visit_foreach(
+    context: &mut EmitContext,
+    label: Option<String>,
+    var: AstNode,
+    target: AstNode,
+    body: AstNode,
+) -> Result<(), HassiumError> {
     let body_label = context.create_label();
     let end_label = context.create_label();
     let tmp = context.tmp_symbol();
 
-    visit(context, target);
+    visit(context, target)?;
+    context.push_scope();
+    let iterator = context.declare_local(&tmp);
+    let first_var = context.next_slot();
+    for name in pattern_names(&var) {
+        context.declare_local(&name);
+    }
     context.add_inst(VMInstruction::Iter);
-    context.add_inst(VMInstruction::StoreId { id: tmp });
+    context.add_inst(VMInstruction::StoreLocal { slot: iterator });
+    context.add_inst(VMInstruction::Pop);
     context.place_label(body_label);
+    context.add_inst(VMInstruction::LoadLocal { slot: iterator });
     context.add_inst(VMInstruction::IterNext {
-        jump_if_full: end_label,
+        jump_if_done: end_label,
     });
-    context.add_inst(VMInstruction::StoreId { id: var });
-    visit(context, body);
+    let fresh_cells = context.begin_fresh_cells(first_var);
+    visit_store(context, var)?;
+    context.add_inst(VMInstruction::Pop);
+    visit_loop_body(context, label, body_label, end_label, body)?;
+    context.end_fresh_cells(fresh_cells);
     context.add_inst(VMInstruction::Jump { to: body_label });
+    context.place_label(end_label);
+    context.pop_scope();
+    Ok(())
 }
 fn visit_func(
     context: &mut EmitContext,
@@ -168,126 +589,548 @@ fn visit_func(
     params: FuncParams,
     return_type: Option<AstNode>,
     body: AstNode,
-) {
-    context.code_obj_stack.push_front(CodeObj::new(false));
-    visit(context, body);
-    let code_obj: CodeObj = context.code_obj_stack.pop_front().unwrap();
+) -> Result<(), HassiumError> {
+    // Declared before the body so that nested functions can call themselves
+    let store = context.resolve_store(&name);
+    context.code_obj_stack.push_front(CodeBuilder::new());
+    for param in &params.names {
+        context.declare_local(param);
+    }
+    if params.variadic {
+        context.declare_local("args");
+    }
+    let loops = std::mem::take(&mut context.loops);
+    let tries = std::mem::take(&mut context.tries);
+    visit(context, body)?;
+    context.loops = loops;
+    context.tries = tries;
+    let (code_obj, captures) = context.finish_code_obj()?;
     let has_return_type: bool = return_type.is_some();
     if has_return_type {
-        visit(context, return_type.unwrap());
+        visit(context, return_type.unwrap())?;
     }
     context.add_inst(VMInstruction::BuildFunc {
-        name,
-        code_obj,
+        name: name.clone(),
+        code_obj: Rc::new(code_obj),
+        captures,
         param_names: params.names,
+        variadic: params.variadic,
         has_return_type,
     });
+    context.add_inst(store);
+    context.add_inst(VMInstruction::Pop);
+    Ok(())
 }
 fn visit_if(
     context: &mut EmitContext,
     predicate: AstNode,
     body: AstNode,
     else_body: Option<AstNode>,
-) {
+) -> Result<(), HassiumError> {
     let else_label = context.create_label();
-    visit(context, predicate);
+    visit(context, predicate)?;
     context.add_inst(VMInstruction::JumpIfFalse { to: else_label });
-    visit(context, body);
-    context.place_label(else_label);
-    if else_body.is_some() {
-        visit(context, else_body.unwrap());
+    visit(context, body)?;
+    match else_body {
+        Some(else_body) => {
+            let end_label = context.create_label();
+            context.add_inst(VMInstruction::Jump { to: end_label });
+            context.place_label(else_label);
+            visit(context, else_body)?;
+            context.place_label(end_label);
+        }
+        None => context.place_label(else_label),
     }
+    Ok(())
 }
-fn visit_import(context: &mut EmitContext, target: AstNode) {
-    visit(context, target);
-    context.add_inst(VMInstruction::Import);
+fn visit_import(context: &mut EmitContext, path: Vec<String>) -> Result<(), HassiumError> {
+    let name = path.last().unwrap().clone();
+    context.add_inst(VMInstruction::Import { path });
+    context.store_name(&name);
+    context.add_inst(VMInstruction::Pop);
+    Ok(())
 }
-fn visit_raise(context: &mut EmitContext, value: AstNode) {
-    visit(context, value);
+fn visit_raise(context: &mut EmitContext, value: AstNode) -> Result<(), HassiumError> {
+    visit(context, value)?;
     context.add_inst(VMInstruction::Raise);
+    Ok(())
 }
-fn visit_return(context: &mut EmitContext, value: AstNode) {
-    visit(context, value);
-    context.add_inst(VMInstruction::Return)
+fn visit_return(context: &mut EmitContext, value: AstNode) -> Result<(), HassiumError> {
+    visit(context, value)?;
+    context.exit_tries(0)?;
+    context.add_inst(VMInstruction::Return);
+    Ok(())
 }
-fn visit_super(context: &mut EmitContext, _args: Vec<AstNode>) {
+fn visit_super(context: &mut EmitContext, _args: Vec<AstNode>) -> Result<(), HassiumError> {
     let mut args = _args.clone();
     args.reverse();
     for arg in args {
-        visit(context, arg.to_owned());
+        visit(context, arg.to_owned())?;
     }
     context.add_inst(VMInstruction::Super {
         arg_count: _args.len() as u32,
     });
+    Ok(())
+}
+/// The finally block is emitted twice, once for when the try and catch blocks
+/// finish and once for when they raise, after which the exception is raised
+/// again. Jumps out of the try statement run their own copy of it
+fn visit_try_catch(
+    context: &mut EmitContext,
+    try_body: AstNode,
+    value: Option<String>,
+    catch_body: Option<AstNode>,
+    finally_body: Option<AstNode>,
+) -> Result<(), HassiumError> {
+    let loops = context.loops.len();
+    context.tries.push(TryBlock {
+        finally_body: finally_body.clone(),
+        loops,
+        gaps: Vec::new(),
+    });
+    let start = context.current_offset();
+    let ret = visit_try_body(context, try_body, value, catch_body);
+    let try_block = context.tries.pop().unwrap();
+    let end = ret?;
+    let finally_body = match finally_body {
+        Some(finally_body) => finally_body,
+        None => return Ok(()),
+    };
+    visit(context, finally_body.clone())?;
+    let end_label = context.create_label();
+    context.add_inst(VMInstruction::Jump { to: end_label });
+    let target = context.current_offset();
+    context.add_handler_around(start, end, &try_block.gaps, target, true);
+    context.push_scope();
+    let tmp = context.tmp_symbol();
+    let slot = context.declare_local(&tmp);
+    context.add_inst(VMInstruction::StoreLocal { slot });
+    context.add_inst(VMInstruction::Pop);
+    visit(context, finally_body)?;
+    context.add_inst(VMInstruction::LoadLocal { slot });
+    context.add_inst(VMInstruction::Reraise);
+    context.pop_scope();
+    context.place_label(end_label);
+    Ok(())
+}
+/// Emits the try and catch blocks of a try statement, returning where they end
+fn visit_try_body(
+    context: &mut EmitContext,
+    try_body: AstNode,
+    value: Option<String>,
+    catch_body: Option<AstNode>,
+) -> Result<u32, HassiumError> {
+    let start = context.current_offset();
+    visit(context, try_body)?;
+    let catch_body = match catch_body {
+        Some(catch_body) => catch_body,
+        None => return Ok(context.current_offset()),
+    };
+    let end = context.current_offset();
+    let end_label = context.create_label();
+    context.add_inst(VMInstruction::Jump { to: end_label });
+    let target = context.current_offset();
+    let gaps = context.tries.last().unwrap().gaps.clone();
+    context.add_handler_around(start, end, &gaps, target, false);
+    // The VM pushes the caught exception before jumping to the catch body
+    context.push_scope();
+    if let Some(value) = value {
+        let slot = context.declare_local(&value);
+        context.add_inst(VMInstruction::StoreLocal { slot });
+    }
+    context.add_inst(VMInstruction::Pop);
+    visit(context, catch_body)?;
+    context.pop_scope();
+    context.place_label(end_label);
+    Ok(context.current_offset())
 }
-fn visit_try_catch(context: &mut EmitContext, node: AstNode) {}
-fn visit_while(context: &mut EmitContext, condition: AstNode, body: AstNode) {
+fn visit_while(
+    context: &mut EmitContext,
+    label: Option<String>,
+    condition: AstNode,
+    body: AstNode,
+) -> Result<(), HassiumError> {
     let body_label = context.create_label();
     let end_label = context.create_label();
     context.place_label(body_label);
-    visit(context, condition);
+    visit(context, condition)?;
     context.add_inst(VMInstruction::JumpIfFalse { to: end_label });
-    visit(context, body);
+    let fresh_cells = context.begin_fresh_cells(context.next_slot());
+    visit_loop_body(context, label, body_label, end_label, body)?;
+    context.end_fresh_cells(fresh_cells);
     context.add_inst(VMInstruction::Jump { to: body_label });
     context.place_label(end_label);
+    Ok(())
 }
-fn visit_expression_statement(context: &mut EmitContext, expression: AstNode) {
-    visit(context, expression);
+fn visit_expression_statement(
+    context: &mut EmitContext,
+    expression: AstNode,
+) -> Result<(), HassiumError> {
+    visit(context, expression)?;
     context.add_inst(VMInstruction::Pop);
+    Ok(())
+}
+fn visit_assign(
+    context: &mut EmitContext,
+    left: AstNode,
+    right: AstNode,
+) -> Result<(), HassiumError> {
+    visit(context, right)?;
+    visit_store(context, left)
 }
-fn visit_assign(context: &mut EmitContext, left: AstNode, right: AstNode) {
-    visit(context, right);
-    match left {
-        AstNode::Id { value } => context.add_inst(VMInstruction::StoreId { id: value }),
-        AstNode::Subscript { target, key } => {
-            visit(context, *key);
-            visit(context, *target);
+/// Stores the value on top of the stack into `left`, leaving it on the stack
+fn visit_store(context: &mut EmitContext, left: AstNode) -> Result<(), HassiumError> {
+    match left.kind {
+        AstKind::Id { value } if KEYWORDS.contains(&value.as_str()) => {
+            return Err(HassiumError::Syntax {
+                message: format!("Cannot assign to {}!", value),
+                location: None,
+            })
+        }
+        AstKind::Id { value } => context.store_name(&value),
+        AstKind::Subscript { target, key } => {
+            visit(context, *key)?;
+            visit(context, *target)?;
             context.add_inst(VMInstruction::StoreSubscript);
         }
-        AstNode::AttribAccess { target, attrib } => {
-            visit(context, *target);
+        AstKind::AttribAccess { target, attrib } => {
+            visit(context, *target)?;
             context.add_inst(VMInstruction::StoreAttrib { attrib });
         }
-        _ => (),
+        // Unpack leaves the first item on top, each is stored and popped in
+        // turn until only the value being destructured is left
+        AstKind::Tuple { items } => {
+            context.add_inst(VMInstruction::Unpack {
+                count: items.len() as u32,
+            });
+            for item in items {
+                visit_store(context, item)?;
+                context.add_inst(VMInstruction::Pop);
+            }
+        }
+        _ => {
+            return Err(HassiumError::Syntax {
+                message: "Invalid assignment target!".to_string(),
+                location: None,
+            })
+        }
     }
+    Ok(())
 }
-fn visit_attrib_access(context: &mut EmitContext, target: AstNode, attrib: String) {
-    visit(context, target);
+fn visit_attrib_access(
+    context: &mut EmitContext,
+    target: AstNode,
+    attrib: String,
+) -> Result<(), HassiumError> {
+    visit(context, target)?;
     context.add_inst(VMInstruction::LoadAttrib { attrib });
+    Ok(())
 }
-fn visit_bin_op(context: &mut EmitContext, op: BinOpType, left: AstNode, right: AstNode) {
-    visit(context, right);
-    visit(context, left);
+fn visit_bin_op(
+    context: &mut EmitContext,
+    op: BinOpType,
+    left: AstNode,
+    right: AstNode,
+) -> Result<(), HassiumError> {
+    match op {
+        BinOpType::And => return visit_logical(context, true, left, right),
+        BinOpType::Or => return visit_logical(context, false, left, right),
+        _ => (),
+    }
+    visit(context, right)?;
+    visit(context, left)?;
     context.add_inst(VMInstruction::BinOp { op });
+    Ok(())
+}
+/// `&&` and `||` only evaluate the right operand when the left one does not
+/// already decide the result
+fn visit_logical(
+    context: &mut EmitContext,
+    is_and: bool,
+    left: AstNode,
+    right: AstNode,
+) -> Result<(), HassiumError> {
+    let right_label = context.create_label();
+    let false_label = context.create_label();
+    let end_label = context.create_label();
+    visit(context, left)?;
+    if is_and {
+        context.add_inst(VMInstruction::JumpIfFalse { to: false_label });
+    } else {
+        context.add_inst(VMInstruction::JumpIfFalse { to: right_label });
+        context.add_inst(VMInstruction::LoadBool { value: true });
+        context.add_inst(VMInstruction::Jump { to: end_label });
+    }
+    context.place_label(right_label);
+    visit(context, right)?;
+    context.add_inst(VMInstruction::JumpIfFalse { to: false_label });
+    context.add_inst(VMInstruction::LoadBool { value: true });
+    context.add_inst(VMInstruction::Jump { to: end_label });
+    context.place_label(false_label);
+    context.add_inst(VMInstruction::LoadBool { value: false });
+    context.place_label(end_label);
+    Ok(())
 }
-fn visit_id(context: &mut EmitContext, value: String) {
+fn visit_id(context: &mut EmitContext, value: String) -> Result<(), HassiumError> {
     match value.as_str() {
+        "false" => context.add_inst(VMInstruction::LoadBool { value: false }),
+        "null" => context.add_inst(VMInstruction::LoadNull),
         "self" => context.add_inst(VMInstruction::SelfRef),
-        _ => context.add_inst(VMInstruction::LoadId { id: value }),
+        "true" => context.add_inst(VMInstruction::LoadBool { value: true }),
+        _ => context.load_name(&value)?,
     };
+    Ok(())
 }
-fn visit_invoke(context: &mut EmitContext, target: AstNode, _args: Vec<AstNode>) {
+/// Every part is pushed as a string or a value to call toString on, then joined
+/// by a single BuildString
+fn visit_interpolation(
+    context: &mut EmitContext,
+    parts: Vec<InterpolationPart>,
+) -> Result<(), HassiumError> {
+    let count = parts.len() as u32;
+    for part in parts {
+        match part {
+            InterpolationPart::Text(value) => context.add_inst(VMInstruction::LoadString { value }),
+            InterpolationPart::Value { value, spec } => {
+                visit(context, value)?;
+                if let Some(spec) = spec {
+                    context.add_inst(VMInstruction::Format { spec });
+                }
+            }
+        }
+    }
+    context.add_inst(VMInstruction::BuildString { count });
+    Ok(())
+}
+fn visit_dict(
+    context: &mut EmitContext,
+    entries: Vec<(AstNode, AstNode)>,
+) -> Result<(), HassiumError> {
+    let count = entries.len() as u32;
+    for (key, value) in entries {
+        visit(context, key)?;
+        visit(context, value)?;
+    }
+    context.add_inst(VMInstruction::BuildDict { count });
+    Ok(())
+}
+fn visit_invoke(
+    context: &mut EmitContext,
+    target: AstNode,
+    _args: Vec<AstNode>,
+) -> Result<(), HassiumError> {
     let args = _args.clone();
     for arg in args {
-        visit(context, arg.to_owned());
+        visit(context, arg.to_owned())?;
     }
-    visit(context, target);
+    visit(context, target)?;
     context.add_inst(VMInstruction::Invoke {
         arg_count: _args.len() as u32,
     });
+    Ok(())
+}
+fn visit_list(context: &mut EmitContext, items: Vec<AstNode>) -> Result<(), HassiumError> {
+    let count = items.len() as u32;
+    for item in items {
+        visit(context, item)?;
+    }
+    context.add_inst(VMInstruction::BuildList { count });
+    Ok(())
 }
-fn visit_number(context: &mut EmitContext, value: f64) {
+fn visit_number(context: &mut EmitContext, value: f64) -> Result<(), HassiumError> {
     context.add_inst(VMInstruction::LoadNumber { value });
+    Ok(())
 }
-fn visit_string(context: &mut EmitContext, value: String) {
+fn visit_string(context: &mut EmitContext, value: String) -> Result<(), HassiumError> {
     context.add_inst(VMInstruction::LoadString { value });
+    Ok(())
 }
-fn visit_subscript(context: &mut EmitContext, key: AstNode, target: AstNode) {
-    visit(context, key);
-    visit(context, target);
+fn visit_subscript(
+    context: &mut EmitContext,
+    key: AstNode,
+    target: AstNode,
+) -> Result<(), HassiumError> {
+    visit(context, key)?;
+    visit(context, target)?;
     context.add_inst(VMInstruction::LoadSubscript);
+    Ok(())
 }
-fn visit_unary_op(context: &mut EmitContext, op: UnaryOpType, target: AstNode) {
-    visit(context, target);
-    context.add_inst(VMInstruction::UnaryOp { op });
+fn visit_tuple(context: &mut EmitContext, items: Vec<AstNode>) -> Result<(), HassiumError> {
+    let count = items.len() as u32;
+    for item in items {
+        visit(context, item)?;
+    }
+    context.add_inst(VMInstruction::BuildTuple { count });
+    Ok(())
+}
+fn visit_unary_op(
+    context: &mut EmitContext,
+    op: UnaryOpType,
+    target: AstNode,
+) -> Result<(), HassiumError> {
+    match (op, target.kind) {
+        // The receiver and key are evaluated once and kept below the value for
+        // the store, so `++a[f()]` only calls `f` once
+        (
+            op @ (UnaryOpType::DecrementPre | UnaryOpType::IncrementPre),
+            AstKind::Subscript { target, key },
+        ) => {
+            visit(context, *key)?;
+            visit(context, *target)?;
+            context.add_inst(VMInstruction::Dup { count: 2 });
+            context.add_inst(VMInstruction::LoadSubscript);
+            context.add_inst(VMInstruction::UnaryOp { op });
+            context.add_inst(VMInstruction::Rotate { count: 2 });
+            context.add_inst(VMInstruction::StoreSubscript);
+            Ok(())
+        }
+        (
+            op @ (UnaryOpType::DecrementPre | UnaryOpType::IncrementPre),
+            AstKind::AttribAccess { target, attrib },
+        ) => {
+            visit(context, *target)?;
+            context.add_inst(VMInstruction::Dup { count: 1 });
+            context.add_inst(VMInstruction::LoadAttrib {
+                attrib: attrib.clone(),
+            });
+            context.add_inst(VMInstruction::UnaryOp { op });
+            context.add_inst(VMInstruction::Rotate { count: 1 });
+            context.add_inst(VMInstruction::StoreAttrib { attrib });
+            Ok(())
+        }
+        (op @ (UnaryOpType::DecrementPre | UnaryOpType::IncrementPre), kind) => {
+            let target = AstNode {
+                kind,
+                span: target.span,
+            };
+            visit(context, target.clone())?;
+            context.add_inst(VMInstruction::UnaryOp { op });
+            visit_store(context, target)
+        }
+        (op, kind) => {
+            let target = AstNode {
+                kind,
+                span: target.span,
+            };
+            visit(context, target)?;
+            context.add_inst(VMInstruction::UnaryOp { op });
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::HassiumError;
+    use crate::runtime::object::convert::FromHassium;
+    use crate::runtime::vm::VMContext;
+
+    fn compile_error(code: &str) -> HassiumError {
+        VMContext::new().run_source(code, "test").unwrap_err()
+    }
+
+    fn run(code: &str) -> VMContext {
+        let mut vm = VMContext::new();
+        vm.run_source(code, "test").unwrap();
+        vm
+    }
+
+    fn number(vm: &mut VMContext, name: &str) -> f64 {
+        let id = vm.get_global(name).unwrap();
+        f64::from_hassium(vm, id).unwrap()
+    }
+
+    #[test]
+    fn functions_assign_to_existing_globals() {
+        let mut vm = run("count = 0;\nfunc inc() { count = count + 1; }\ninc();\ninc();");
+        assert_eq!(number(&mut vm, "count"), 2.0);
+    }
+
+    #[test]
+    fn functions_shadow_builtins_with_locals() {
+        let mut vm = run("func f() { range = 5; return range; }\nx = f();\ny = 0;\nforeach (i in range(3)) { y = y + i; }");
+        assert_eq!(number(&mut vm, "x"), 5.0);
+        assert_eq!(number(&mut vm, "y"), 3.0);
+    }
+
+    #[test]
+    fn class_bodies_declare_members() {
+        let mut vm = run("y = 1;\nclass K { y = 3; }\nmember = K.y;");
+        assert_eq!(number(&mut vm, "y"), 1.0);
+        assert_eq!(number(&mut vm, "member"), 3.0);
+    }
+
+    #[test]
+    fn increments_evaluate_their_target_once() {
+        let mut vm = run("calls = 0;
+            class Box { func new() { self.n = 1; } }
+            box = Box();
+            items = [10, 20];
+            func index() { calls = calls + 1; return 1; }
+            func get() { calls = calls + 1; return box; }
+            a = ++items[index()];
+            b = --get().n;
+            c = items[1];");
+        assert_eq!(number(&mut vm, "calls"), 2.0);
+        assert_eq!(number(&mut vm, "a"), 21.0);
+        assert_eq!(number(&mut vm, "b"), 0.0);
+        assert_eq!(number(&mut vm, "c"), 21.0);
+    }
+
+    #[test]
+    fn invalid_assignment_targets_are_syntax_errors() {
+        let error = compile_error("x = 1;\nx + 1 = 2;");
+        assert!(matches!(error, HassiumError::Syntax { .. }));
+        assert_eq!(
+            error.to_string(),
+            "test:2:7: SyntaxError: Invalid assignment target!"
+        );
+    }
+
+    #[test]
+    fn keywords_cannot_be_assigned_to() {
+        let error = compile_error("null = 1;");
+        assert_eq!(
+            error.to_string(),
+            "test:1:6: SyntaxError: Cannot assign to null!"
+        );
+    }
+
+    #[test]
+    fn labelled_break_and_continue_reach_outer_loops() {
+        let mut vm = run("pairs = 0;
+            last = 0;
+            outer: for (i = 0; i < 5; i = i + 1) {
+                j = 0;
+                inner: while (true) {
+                    j = j + 1;
+                    if (j > i) { continue outer; }
+                    if (i == 4) {
+                        last = i;
+                        break outer;
+                    }
+                    foreach (k in range(3)) {
+                        if (k == 1) { continue inner; }
+                        pairs = pairs + 1;
+                    }
+                }
+            }");
+        assert_eq!(number(&mut vm, "pairs"), 6.0);
+        assert_eq!(number(&mut vm, "last"), 4.0);
+    }
+
+    #[test]
+    fn unknown_loop_labels_are_syntax_errors() {
+        let error = compile_error("outer: while (true) {\n    break inner;\n}");
+        assert!(matches!(error, HassiumError::Syntax { .. }));
+        assert_eq!(
+            error.to_string(),
+            "test:2:5: SyntaxError: break refers to unknown loop inner!"
+        );
+        let error = compile_error("func f() {\n    continue;\n}");
+        assert_eq!(
+            error.to_string(),
+            "test:2:5: SyntaxError: continue used outside of a loop!"
+        );
+    }
 }
@@ -0,0 +1,229 @@
+use core::fmt;
+use std::sync::Arc;
+
+use crate::runtime::object::ObjectId;
+
+/// A position in a source file. `offset` is in bytes, `line` and `column`
+/// count from 1 for display
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub file: Arc<str>,
+    pub offset: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    /// The start of `file`
+    pub fn start(file: &str) -> Span {
+        Span {
+            file: Arc::from(file),
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Every way compiling or running a script can fail. Name, type and runtime
+/// errors are raised as Hassium exceptions first, `exception` holds the raised
+/// object once there is one so that it survives being passed through natives
+#[derive(Clone, Debug)]
+pub enum HassiumError {
+    Lex {
+        message: String,
+        location: Span,
+    },
+    Syntax {
+        message: String,
+        location: Option<Span>,
+    },
+    Name {
+        message: String,
+        location: Option<Span>,
+        exception: Option<ObjectId>,
+    },
+    Type {
+        message: String,
+        location: Option<Span>,
+        exception: Option<ObjectId>,
+    },
+    Runtime {
+        message: String,
+        location: Option<Span>,
+        exception: Option<ObjectId>,
+    },
+}
+
+impl HassiumError {
+    pub fn name_error(message: String) -> HassiumError {
+        HassiumError::Name {
+            message,
+            location: None,
+            exception: None,
+        }
+    }
+
+    pub fn type_error(message: String) -> HassiumError {
+        HassiumError::Type {
+            message,
+            location: None,
+            exception: None,
+        }
+    }
+
+    pub fn runtime_error(message: String) -> HassiumError {
+        HassiumError::Runtime {
+            message,
+            location: None,
+            exception: None,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            HassiumError::Lex { message, .. }
+            | HassiumError::Syntax { message, .. }
+            | HassiumError::Name { message, .. }
+            | HassiumError::Type { message, .. }
+            | HassiumError::Runtime { message, .. } => message,
+        }
+    }
+
+    pub fn location(&self) -> Option<&Span> {
+        match self {
+            HassiumError::Lex { location, .. } => Some(location),
+            HassiumError::Syntax { location, .. }
+            | HassiumError::Name { location, .. }
+            | HassiumError::Type { location, .. }
+            | HassiumError::Runtime { location, .. } => location.as_ref(),
+        }
+    }
+
+    /// Locates an error that does not know where it happened yet, such as one
+    /// returned by a native function, at the code that was running
+    pub fn with_location(mut self, span: Option<Span>) -> HassiumError {
+        match &mut self {
+            HassiumError::Lex { .. } => (),
+            HassiumError::Syntax { location, .. }
+            | HassiumError::Name { location, .. }
+            | HassiumError::Type { location, .. }
+            | HassiumError::Runtime { location, .. } => {
+                if location.is_none() {
+                    *location = span;
+                }
+            }
+        }
+        self
+    }
+
+    /// The raised Hassium object, None for compile errors and for runtime
+    /// errors that have not reached the VM's unwinder yet
+    pub fn exception(&self) -> Option<ObjectId> {
+        match self {
+            HassiumError::Lex { .. } | HassiumError::Syntax { .. } => None,
+            HassiumError::Name { exception, .. }
+            | HassiumError::Type { exception, .. }
+            | HassiumError::Runtime { exception, .. } => *exception,
+        }
+    }
+
+    /// Attaches the raised object to a runtime error, compile errors are
+    /// returned unchanged
+    pub fn with_exception(self, exception: ObjectId) -> HassiumError {
+        match self {
+            HassiumError::Name {
+                message, location, ..
+            } => HassiumError::Name {
+                message,
+                location,
+                exception: Some(exception),
+            },
+            HassiumError::Type {
+                message, location, ..
+            } => HassiumError::Type {
+                message,
+                location,
+                exception: Some(exception),
+            },
+            HassiumError::Runtime {
+                message, location, ..
+            } => HassiumError::Runtime {
+                message,
+                location,
+                exception: Some(exception),
+            },
+            error => error,
+        }
+    }
+
+    pub fn with_message(mut self, new_message: String) -> HassiumError {
+        match &mut self {
+            HassiumError::Lex { message, .. }
+            | HassiumError::Syntax { message, .. }
+            | HassiumError::Name { message, .. }
+            | HassiumError::Type { message, .. }
+            | HassiumError::Runtime { message, .. } => *message = new_message,
+        }
+        self
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            HassiumError::Lex { .. } => "LexError",
+            HassiumError::Syntax { .. } => "SyntaxError",
+            HassiumError::Name { .. } => "NameError",
+            HassiumError::Type { .. } => "TypeError",
+            HassiumError::Runtime { .. } => "RuntimeError",
+        }
+    }
+}
+
+impl fmt::Display for HassiumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location() {
+            Some(location) => write!(f, "{}: {}: {}", location, self.kind(), self.message()),
+            None => write!(f, "{}: {}", self.kind(), self.message()),
+        }
+    }
+}
+
+impl std::error::Error for HassiumError {}
+
+/// Lets natives return plain messages with `?`, these become runtime errors
+impl From<String> for HassiumError {
+    fn from(message: String) -> HassiumError {
+        HassiumError::runtime_error(message)
+    }
+}
+
+impl From<&str> for HassiumError {
+    fn from(message: &str) -> HassiumError {
+        HassiumError::runtime_error(message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn errors_can_be_sent_between_threads() {
+        assert_send_sync::<HassiumError>();
+        let error = std::thread::spawn(|| HassiumError::Lex {
+            message: "Unknown char \"`\"!".to_string(),
+            location: Span::start("test"),
+        })
+        .join()
+        .unwrap();
+        assert_eq!(error.to_string(), "test:1:1: LexError: Unknown char \"`\"!");
+    }
+}
@@ -1,9 +1,21 @@
+mod number;
+mod string;
+
 use std::fmt;
 
+use crate::error::{HassiumError, Span};
+
+use self::number::read_number;
+use self::string::{read_interpolated_str, read_str};
+
 #[derive(Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub value: String,
+    pub span: Span,
+    /// The text of the `/** */` doc comments right before the token, for
+    /// formatters and doc generators. The parser ignores it
+    pub doc: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -15,9 +27,19 @@ pub enum TokenType {
     Colon,
     Comma,
     Dot,
-    EOF,
+    Eof,
+    /// The `spec` of a `{value:spec}` in an interpolated string
+    FormatSpec,
     Id,
-    Number,
+    /// The `}` ending an expression in an interpolated string
+    InterpolationClose,
+    /// The quote ending an interpolated string
+    InterpolationEnd,
+    /// The `{` starting an expression in an interpolated string
+    InterpolationOpen,
+    /// The `$"` starting an interpolated string
+    InterpolationStart,
+    Number(NumberKind),
     Op,
     OpenBrace,
     OpenSquare,
@@ -27,6 +49,12 @@ pub enum TokenType {
     Variadic,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumberKind {
+    Integer,
+    Float,
+}
+
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -34,167 +62,215 @@ impl fmt::Display for TokenType {
 }
 
 struct LexerContext {
-    pub code: String,
+    /// The source decoded up front so that characters are read by index
+    pub code: Vec<char>,
     pub pos: usize,
     pub len: usize,
     pub tokens: Vec<Token>,
+    pub location: Span,
+    pub token_start: Span,
+    /// Doc comments read since the last token, see Token::doc
+    pub doc: Option<String>,
 }
 
 impl LexerContext {
     fn peek(&mut self) -> u32 {
         if self.pos < self.len {
-            return self.code.chars().nth(self.pos).unwrap().try_into().unwrap();
+            return self.code[self.pos].into();
         }
-        return 0;
+        0
     }
 
     fn peek_next(&mut self) -> u32 {
         if (self.pos + 1) < self.len {
-            return self
-                .code
-                .chars()
-                .nth(self.pos + 1)
-                .unwrap()
-                .try_into()
-                .unwrap();
+            return self.code[self.pos + 1].into();
+        }
+        0
+    }
+
+    /// Looks `offset` characters ahead, 0 past the end like peek
+    fn peek_at(&mut self, offset: usize) -> u32 {
+        match self.code.get(self.pos + offset) {
+            Some(c) => *c as u32,
+            None => 0,
         }
-        return 0;
     }
 
     fn read(&mut self) -> u32 {
         if self.pos < self.len {
-            let ret = self.code.chars().nth(self.pos).unwrap().try_into().unwrap();
+            let ret = self.code[self.pos].into();
             self.pos += 1;
+            self.location.offset += char::from_u32(ret).unwrap().len_utf8();
+            if ret == '\n' as u32 {
+                self.location.line += 1;
+                self.location.column = 1;
+            } else {
+                self.location.column += 1;
+            }
             return ret;
         }
-        return 0;
+        0
     }
 
+    /// Tokens are located at the position the lexer was at when it started
+    /// reading them, see token_start
     fn add_tok(&mut self, token_type: TokenType, value: &str) {
         self.tokens.push(Token {
-            token_type: token_type,
+            token_type,
             value: value.to_string(),
+            span: self.token_start.clone(),
+            doc: self.doc.take(),
         });
     }
 }
 
-pub fn tokenize(code: String) -> Vec<Token> {
+/// Splits `code` into tokens whose spans name `file` as their source
+pub fn tokenize(code: String, file: &str) -> Result<Vec<Token>, HassiumError> {
+    let code: Vec<char> = code.chars().collect();
     let len = code.len();
+    let start = Span::start(file);
     let mut context: LexerContext = LexerContext {
         code,
         pos: 0,
         len,
         tokens: Vec::new(),
+        location: start.clone(),
+        token_start: start,
+        doc: None,
     };
 
-    while context.pos < context.len {
-        whitespace(&mut context);
-        let cur: char = context.peek().try_into().unwrap();
-        let next: char = context.peek_next().try_into().unwrap();
+    loop {
+        trivia(&mut context)?;
+        if context.pos >= context.len {
+            break;
+        }
+        read_token(&mut context)?;
+    }
+    context.token_start = context.location.clone();
+    context.add_tok(TokenType::Eof, "EOF");
+    Ok(context.tokens)
+}
 
-        if cur.is_ascii_alphabetic() || cur == '_' {
-            read_id(&mut context);
-        } else if cur.is_ascii_digit() {
-            read_number(&mut context);
-        } else {
-            match cur {
-                '"' => read_str(&mut context, '"'),
-                '\'' => read_str(&mut context, '\''),
-                '=' => {
-                    if next == '=' {
-                        context.add_tok(TokenType::Op, "==");
-                        context.read();
-                    } else {
-                        context.add_tok(TokenType::Assign, "=");
-                        context.read();
-                    }
-                }
-                '+' | '-' | '*' | '/' | '%' | '^' => {
-                    if next == '=' {
-                        context.add_tok(TokenType::Assign, &format!("{}{}", cur, next));
-                        context.read();
-                        context.read();
-                    } else {
-                        context.add_tok(TokenType::Op, &cur.to_string());
-                        context.read();
-                    }
-                }
-                '&' | '|' => {
-                    if next == '=' {
-                        context.add_tok(TokenType::Assign, &format!("{}{}", cur, next));
-                        context.read();
-                        context.read();
-                    } else if next == cur {
-                        context.add_tok(TokenType::Op, &format!("{}{}", cur, next));
-                        context.read();
-                        context.read();
-                    } else {
-                        context.add_tok(TokenType::Op, &cur.to_string());
-                        context.read();
-                    }
-                }
-                '!' | '>' | '<' => {
-                    if next == '=' {
-                        context.add_tok(TokenType::Op, &format!("{}{}", cur, next));
-                        context.read();
-                        context.read();
-                    } else {
-                        context.add_tok(TokenType::Op, &cur.to_string());
-                        context.read();
-                    }
-                }
-                '{' => {
-                    context.add_tok(TokenType::OpenBrace, "{");
+/// Reads the token starting at the current character
+fn read_token(context: &mut LexerContext) -> Result<(), HassiumError> {
+    context.token_start = context.location.clone();
+    let cur: char = context.peek().try_into().unwrap();
+    let next: char = context.peek_next().try_into().unwrap();
+
+    if cur == '$' && (next == '"' || next == '\'') {
+        read_interpolated_str(context)?;
+    } else if cur == 'r' && (next == '"' || next == '\'') {
+        read_str(context, true)?;
+    } else if cur.is_ascii_alphabetic() || cur == '_' {
+        read_id(context);
+    } else if cur.is_ascii_digit() {
+        read_number(context)?;
+    } else {
+        match cur {
+            '"' | '\'' => read_str(context, false)?,
+            '=' => {
+                if next == '=' {
+                    context.add_tok(TokenType::Op, "==");
                     context.read();
-                }
-                '}' => {
-                    context.add_tok(TokenType::CloseBrace, "}");
                     context.read();
-                }
-                '(' => {
-                    context.add_tok(TokenType::OpenParen, "(");
+                } else {
+                    context.add_tok(TokenType::Assign, "=");
                     context.read();
                 }
-                ')' => {
-                    context.add_tok(TokenType::CloseParen, ")");
+            }
+            '+' | '-' | '*' | '/' | '%' | '^' => {
+                if (cur == '+' || cur == '-') && next == cur {
+                    context.add_tok(TokenType::Op, &format!("{}{}", cur, next));
                     context.read();
-                }
-                '[' => {
-                    context.add_tok(TokenType::OpenSquare, "[");
                     context.read();
-                }
-                ']' => {
-                    context.add_tok(TokenType::CloseSquare, "]");
+                } else if next == '=' {
+                    context.add_tok(TokenType::Assign, &format!("{}{}", cur, next));
                     context.read();
-                }
-                ',' => {
-                    context.add_tok(TokenType::Comma, ",");
+                    context.read();
+                } else {
+                    context.add_tok(TokenType::Op, &cur.to_string());
                     context.read();
                 }
-                '.' => {
-                    if next == '.' {
-                        context.add_tok(TokenType::Variadic, "..");
-                        context.read();
-                        context.read();
-                    } else {
-                        context.add_tok(TokenType::Dot, ".");
-                        context.read();
-                    }
+            }
+            '&' | '|' => {
+                if next == '=' {
+                    context.add_tok(TokenType::Assign, &format!("{}{}", cur, next));
+                    context.read();
+                    context.read();
+                } else if next == cur {
+                    context.add_tok(TokenType::Op, &format!("{}{}", cur, next));
+                    context.read();
+                    context.read();
+                } else {
+                    context.add_tok(TokenType::Op, &cur.to_string());
+                    context.read();
                 }
-                ':' => {
-                    context.add_tok(TokenType::Colon, ":");
+            }
+            '!' | '>' | '<' => {
+                if next == '=' {
+                    context.add_tok(TokenType::Op, &format!("{}{}", cur, next));
+                    context.read();
+                    context.read();
+                } else {
+                    context.add_tok(TokenType::Op, &cur.to_string());
                     context.read();
                 }
-                ';' => {
-                    context.add_tok(TokenType::Semicolon, ";");
+            }
+            '{' => {
+                context.add_tok(TokenType::OpenBrace, "{");
+                context.read();
+            }
+            '}' => {
+                context.add_tok(TokenType::CloseBrace, "}");
+                context.read();
+            }
+            '(' => {
+                context.add_tok(TokenType::OpenParen, "(");
+                context.read();
+            }
+            ')' => {
+                context.add_tok(TokenType::CloseParen, ")");
+                context.read();
+            }
+            '[' => {
+                context.add_tok(TokenType::OpenSquare, "[");
+                context.read();
+            }
+            ']' => {
+                context.add_tok(TokenType::CloseSquare, "]");
+                context.read();
+            }
+            ',' => {
+                context.add_tok(TokenType::Comma, ",");
+                context.read();
+            }
+            '.' => {
+                if next == '.' {
+                    context.add_tok(TokenType::Variadic, "..");
+                    context.read();
+                    context.read();
+                } else {
+                    context.add_tok(TokenType::Dot, ".");
                     context.read();
                 }
-                _ => panic!("Unknown char \"{}\"!", cur),
+            }
+            ':' => {
+                context.add_tok(TokenType::Colon, ":");
+                context.read();
+            }
+            ';' => {
+                context.add_tok(TokenType::Semicolon, ";");
+                context.read();
+            }
+            _ => {
+                return Err(HassiumError::Lex {
+                    message: format!("Unknown char \"{}\"!", cur),
+                    location: context.location.clone(),
+                })
             }
         }
     }
-    context.add_tok(TokenType::EOF, "EOF");
-    return context.tokens;
+    Ok(())
 }
 
 fn whitespace(context: &mut LexerContext) {
@@ -203,6 +279,72 @@ fn whitespace(context: &mut LexerContext) {
     }
 }
 
+/// Skips whitespace and comments. `#` and `//` comment out the rest of the line
+fn trivia(context: &mut LexerContext) -> Result<(), HassiumError> {
+    loop {
+        whitespace(context);
+        let cur = context.peek();
+        let next = context.peek_next();
+        if cur == '#' as u32 || (cur == '/' as u32 && next == '/' as u32) {
+            while context.peek() != 0 && context.peek() != '\n' as u32 {
+                context.read();
+            }
+        } else if cur == '/' as u32 && next == '*' as u32 {
+            read_block_comment(context)?;
+        } else {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a `/* */` comment, which may contain other block comments. Comments
+/// starting with `/**` are doc comments and their text is kept for the next token
+fn read_block_comment(context: &mut LexerContext) -> Result<(), HassiumError> {
+    let start = context.location.clone();
+    context.read();
+    context.read();
+    // `/**/` and `/***` are plain comments
+    let is_doc = context.peek() == '*' as u32
+        && context.peek_next() != '*' as u32
+        && context.peek_next() != '/' as u32;
+    if is_doc {
+        context.read();
+    }
+    let mut text: String = String::new();
+    let mut depth = 1;
+    loop {
+        let cur = context.peek();
+        let next = context.peek_next();
+        if cur == 0 {
+            return Err(HassiumError::Lex {
+                message: "Unterminated block comment!".to_string(),
+                location: start,
+            });
+        } else if cur == '/' as u32 && next == '*' as u32 {
+            depth += 1;
+        } else if cur == '*' as u32 && next == '/' as u32 {
+            depth -= 1;
+            if depth == 0 {
+                context.read();
+                context.read();
+                break;
+            }
+        } else {
+            text.push(context.read().try_into().unwrap());
+            continue;
+        }
+        text.push(context.read().try_into().unwrap());
+        text.push(context.read().try_into().unwrap());
+    }
+    if is_doc {
+        context.doc = Some(match context.doc.take() {
+            Some(doc) => format!("{}\n{}", doc, text),
+            None => text,
+        });
+    }
+    Ok(())
+}
+
 fn read_id(context: &mut LexerContext) {
     let mut string: String = String::new();
 
@@ -215,45 +357,211 @@ fn read_id(context: &mut LexerContext) {
         string.push(context.read().try_into().unwrap());
     }
 
-    context.tokens.push(Token {
-        token_type: TokenType::Id,
-        value: string,
-    });
+    context.add_tok(TokenType::Id, &string);
 }
 
-fn read_number(context: &mut LexerContext) {
-    let mut string: String = String::new();
+pub fn print_tokens(tokens: &[Token]) {
+    eprintln!("Lexer tokens:");
+    for token in tokens {
+        if let Some(doc) = &token.doc {
+            eprintln!("Doc: {}", doc);
+        }
+        eprintln!("Type: {}, Value: {}", token.token_type.clone(), token.value);
+    }
+}
 
-    while context.peek() != 0
-        && (char::from_u32(context.peek()).unwrap().is_ascii_digit()
-            || context.peek() == '.' as u32)
-    {
-        string.push(context.read().try_into().unwrap());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex_error(code: &str) -> HassiumError {
+        tokenize(code.to_string(), "test")
+            .err()
+            .expect("expected a lex error")
     }
 
-    context.tokens.push(Token {
-        token_type: TokenType::Number,
-        value: string,
-    });
-}
+    #[test]
+    fn unknown_characters_are_lex_errors() {
+        let error = lex_error("x = 1;\ny = 2 ` 3;");
+        assert!(matches!(error, HassiumError::Lex { .. }));
+        assert_eq!(error.to_string(), "test:2:7: LexError: Unknown char \"`\"!");
+    }
 
-fn read_str(context: &mut LexerContext, delin: char) {
-    let mut string: String = String::new();
-    context.read();
-    while context.peek() != 0 && char::from_u32(context.peek()).unwrap() != delin {
-        string.push(context.read().try_into().unwrap());
+    #[test]
+    fn spans_count_characters_after_multibyte_text() {
+        let tokens = tokenize("s = \"héllo wörld\"; x".to_string(), "test").unwrap();
+        let x = &tokens[tokens.len() - 2];
+        assert_eq!(x.value, "x");
+        assert_eq!((x.span.line, x.span.column, x.span.offset), (1, 20, 21));
     }
-    context.read();
 
-    context.tokens.push(Token {
-        token_type: TokenType::String,
-        value: string,
-    });
-}
+    #[test]
+    fn unterminated_strings_point_at_their_start() {
+        let error = lex_error("x = \"abc\ny = 2;");
+        assert_eq!(
+            error.to_string(),
+            "test:1:5: LexError: Unterminated string!"
+        );
+    }
 
-pub fn print_tokens(tokens: &mut Vec<Token>) {
-    println!("Lexer tokens:");
-    for token in tokens {
-        println!("Type: {}, Value: {}", token.token_type.clone(), token.value);
+    fn strings(code: &str) -> Vec<String> {
+        tokenize(code.to_string(), "test")
+            .unwrap()
+            .into_iter()
+            .filter(|token| token.token_type == TokenType::String)
+            .map(|token| token.value)
+            .collect()
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        let tokens = tokenize(
+            "# hash\nx // line\n/* block /* nested */ still comment */ y /**/ z".to_string(),
+            "test",
+        )
+        .unwrap();
+        let values: Vec<&str> = tokens.iter().map(|token| token.value.as_str()).collect();
+        assert_eq!(values, ["x", "y", "z", "EOF"]);
+        assert_eq!(tokens[1].span.line, 3);
+        assert!(tokens.iter().all(|token| token.doc.is_none()));
+    }
+
+    #[test]
+    fn unterminated_block_comments_point_at_their_start() {
+        let error = lex_error("x = 1;\n  /* outer /* inner */\ny = 2;");
+        assert!(matches!(error, HassiumError::Lex { .. }));
+        assert_eq!(
+            error.to_string(),
+            "test:2:3: LexError: Unterminated block comment!"
+        );
+    }
+
+    #[test]
+    fn doc_comments_are_kept_for_the_next_token() {
+        let tokens = tokenize(
+            "/** Adds. */\n/** Twice. */ func add() {}\n/*** plain */ x".to_string(),
+            "test",
+        )
+        .unwrap();
+        assert_eq!(tokens[0].value, "func");
+        assert_eq!(tokens[0].doc.as_deref(), Some(" Adds. \n Twice. "));
+        assert!(tokens[1..].iter().all(|token| token.doc.is_none()));
+    }
+
+    #[test]
+    fn escape_sequences_are_replaced() {
+        assert_eq!(
+            strings(r#"a = "tab\tnl\nq\"s\'b\\z\0u\u{E9}\u{1F600}";"#),
+            ["tab\tnl\nq\"s'b\\z\0u\u{e9}\u{1f600}"]
+        );
+        let error = lex_error(r#"a = 1; b = "ok\q";"#);
+        assert_eq!(
+            error.to_string(),
+            "test:1:15: LexError: Invalid escape sequence \"\\q\"!"
+        );
+        let error = lex_error(r#"a = "\u{110000}";"#);
+        assert_eq!(
+            error.to_string(),
+            "test:1:6: LexError: \"\\u{110000}\" is not a valid code point!"
+        );
+        let error = lex_error(r#"a = "\u41";"#);
+        assert!(error
+            .message()
+            .starts_with("Unicode escapes are written as"));
+    }
+
+    #[test]
+    fn raw_strings_keep_backslashes() {
+        assert_eq!(
+            strings(r#"a = r"C:\new\table"; b = r'\d+\s';"#),
+            [r"C:\new\table", r"\d+\s"]
+        );
+    }
+
+    #[test]
+    fn triple_quoted_strings_span_lines_and_lose_their_indent() {
+        let code = "a = \"\"\"\n    first\n      second \"quoted\"\n\n    last\\n\n    \"\"\";\nb = '''one line''';";
+        assert_eq!(
+            strings(code),
+            ["first\n  second \"quoted\"\n\nlast\n", "one line"]
+        );
+        let error = lex_error("a = \"\"\"never\nclosed\"\";");
+        assert_eq!(
+            error.to_string(),
+            "test:1:5: LexError: Unterminated string!"
+        );
+    }
+
+    #[test]
+    fn numbers_are_read_in_every_radix() {
+        let tokens = tokenize(
+            "0xFF 0B1010 0o17 1_000 2.5e-3 1E1_0 3 .. 4.x".to_string(),
+            "test",
+        )
+        .unwrap();
+        let numbers: Vec<(&str, NumberKind)> = tokens
+            .iter()
+            .filter_map(|token| match token.token_type {
+                TokenType::Number(kind) => Some((token.value.as_str(), kind)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            numbers,
+            [
+                ("0xFF", NumberKind::Integer),
+                ("0B1010", NumberKind::Integer),
+                ("0o17", NumberKind::Integer),
+                ("1000", NumberKind::Integer),
+                ("2.5e-3", NumberKind::Float),
+                ("1E10", NumberKind::Float),
+                ("3", NumberKind::Integer),
+                ("4", NumberKind::Integer),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_numbers_are_lex_errors() {
+        for (code, expected) in [
+            (
+                "x = 0b;",
+                "test:1:7: LexError: Expected binary digits after 0b!",
+            ),
+            (
+                "x = 0x_1;",
+                "test:1:7: LexError: Digit separator \"_\" must be between two digits!",
+            ),
+            (
+                "x = 1__0;",
+                "test:1:6: LexError: Digit separator \"_\" must be between two digits!",
+            ),
+            (
+                "x = 1_;",
+                "test:1:6: LexError: Digit separator \"_\" must be between two digits!",
+            ),
+            (
+                "x = 1e+;",
+                "test:1:8: LexError: Expected digits in the exponent of 1e+!",
+            ),
+            (
+                "x = 1.5.2;",
+                "test:1:8: LexError: Number 1.5 cannot have another decimal point!",
+            ),
+            (
+                "x = 0b102;",
+                "test:1:9: LexError: Invalid digit \"2\" in binary number 0b10!",
+            ),
+            (
+                "x = 0o19;",
+                "test:1:8: LexError: Invalid digit \"9\" in octal number 0o1!",
+            ),
+            (
+                "x = 12px;",
+                "test:1:7: LexError: Invalid character \"p\" after number 12!",
+            ),
+        ] {
+            assert_eq!(lex_error(code).to_string(), expected);
+        }
     }
 }
@@ -0,0 +1,125 @@
+use crate::error::HassiumError;
+
+use super::{LexerContext, NumberKind, TokenType};
+
+fn error(context: &LexerContext, message: String) -> HassiumError {
+    HassiumError::Lex {
+        message,
+        location: context.location.clone(),
+    }
+}
+
+fn is_digit(c: u32, radix: u32) -> bool {
+    char::from_u32(c).is_some_and(|c| c.is_digit(radix))
+}
+
+fn radix_name(radix: u32) -> &'static str {
+    match radix {
+        2 => "binary",
+        8 => "octal",
+        16 => "hexadecimal",
+        _ => "decimal",
+    }
+}
+
+/// Reads digits of `radix` into `literal`, returning how many there were. A `_`
+/// may separate two digits and is left out of the literal
+fn read_digits(
+    context: &mut LexerContext,
+    literal: &mut String,
+    radix: u32,
+) -> Result<usize, HassiumError> {
+    let mut count = 0;
+    loop {
+        let cur = context.peek();
+        if is_digit(cur, radix) {
+            literal.push(context.read().try_into().unwrap());
+            count += 1;
+        } else if cur == '_' as u32 {
+            if count == 0 || !is_digit(context.peek_next(), radix) {
+                return Err(error(
+                    context,
+                    "Digit separator \"_\" must be between two digits!".to_string(),
+                ));
+            }
+            context.read();
+        } else {
+            return Ok(count);
+        }
+    }
+}
+
+/// Reads a number literal. Integers are decimal or prefixed with `0x`, `0b` or
+/// `0o`, decimal numbers with a fraction or an exponent are floats. The token
+/// holds the literal without its digit separators
+pub(super) fn read_number(context: &mut LexerContext) -> Result<(), HassiumError> {
+    let mut literal: String = String::new();
+    let mut kind = NumberKind::Integer;
+    let radix = match char::from_u32(context.peek_next()) {
+        _ if context.peek() != '0' as u32 => 10,
+        Some('x' | 'X') => 16,
+        Some('b' | 'B') => 2,
+        Some('o' | 'O') => 8,
+        _ => 10,
+    };
+
+    if radix != 10 {
+        literal.push(context.read().try_into().unwrap());
+        literal.push(context.read().try_into().unwrap());
+        let count = read_digits(context, &mut literal, radix)?;
+        if count == 0 && !is_digit(context.peek(), 10) {
+            return Err(error(
+                context,
+                format!("Expected {} digits after {}!", radix_name(radix), literal),
+            ));
+        }
+    } else {
+        read_digits(context, &mut literal, 10)?;
+        // `1..` and `1.toString` are not fractions
+        if context.peek() == '.' as u32 && is_digit(context.peek_next(), 10) {
+            literal.push(context.read().try_into().unwrap());
+            read_digits(context, &mut literal, 10)?;
+            kind = NumberKind::Float;
+        }
+        if context.peek() == 'e' as u32 || context.peek() == 'E' as u32 {
+            literal.push(context.read().try_into().unwrap());
+            if context.peek() == '+' as u32 || context.peek() == '-' as u32 {
+                literal.push(context.read().try_into().unwrap());
+            }
+            if read_digits(context, &mut literal, 10)? == 0 {
+                return Err(error(
+                    context,
+                    format!("Expected digits in the exponent of {}!", literal),
+                ));
+            }
+            kind = NumberKind::Float;
+        }
+        if context.peek() == '.' as u32 && is_digit(context.peek_next(), 10) {
+            return Err(error(
+                context,
+                format!("Number {} cannot have another decimal point!", literal),
+            ));
+        }
+    }
+
+    // Numbers cannot run into a name or more digits, as in `12px` or `0b102`
+    let next = char::from_u32(context.peek()).unwrap();
+    if next.is_ascii_digit() {
+        return Err(error(
+            context,
+            format!(
+                "Invalid digit \"{}\" in {} number {}!",
+                next,
+                radix_name(radix),
+                literal
+            ),
+        ));
+    } else if next.is_alphanumeric() || next == '_' {
+        return Err(error(
+            context,
+            format!("Invalid character \"{}\" after number {}!", next, literal),
+        ));
+    }
+    context.add_tok(TokenType::Number(kind), &literal);
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use crate::error::{HassiumError, Span};
+
+use super::{read_token, trivia, LexerContext, TokenType};
+
+/// A character of a string literal and whether it was written as an escape.
+/// Escaped characters are never taken for indentation or line breaks
+type StrChar = (char, bool);
+
+fn unterminated(context: &LexerContext) -> HassiumError {
+    HassiumError::Lex {
+        message: "Unterminated string!".to_string(),
+        location: context.token_start.clone(),
+    }
+}
+
+/// Reads a string literal quoted with `"` or `'`. Raw strings, prefixed with
+/// `r`, keep backslashes as they are. Strings quoted with three quotes may span
+/// lines and have their indentation stripped, other strings end with the line
+pub(super) fn read_str(context: &mut LexerContext, raw: bool) -> Result<(), HassiumError> {
+    if raw {
+        context.read();
+    }
+    let delim = context.read();
+    let triple = context.peek() == delim && context.peek_next() == delim;
+    if triple {
+        context.read();
+        context.read();
+    }
+
+    let mut chars: Vec<StrChar> = Vec::new();
+    loop {
+        let cur = context.peek();
+        if cur == 0 || (cur == '\n' as u32 && !triple) {
+            return Err(unterminated(context));
+        }
+        if cur == delim
+            && (!triple || (context.peek_next() == delim && context.peek_at(2) == delim))
+        {
+            let len = if triple { 3 } else { 1 };
+            for _ in 0..len {
+                context.read();
+            }
+            break;
+        }
+        if cur == '\\' as u32 && !raw {
+            chars.push((read_escape(context)?, true));
+        } else {
+            chars.push((context.read().try_into().unwrap(), false));
+        }
+    }
+
+    if triple {
+        chars = strip_indent(chars);
+    }
+    let string: String = chars.into_iter().map(|(c, _)| c).collect();
+    context.add_tok(TokenType::String, &string);
+    Ok(())
+}
+
+/// Reads `$"..."`, split into a String token for each run of text and the
+/// tokens of each `{expression}` between InterpolationOpen and InterpolationClose.
+/// `{value:spec}` adds a FormatSpec token and `{{` and `}}` stand for braces
+pub(super) fn read_interpolated_str(context: &mut LexerContext) -> Result<(), HassiumError> {
+    let start = context.token_start.clone();
+    context.read();
+    let delim = context.read();
+    context.add_tok(TokenType::InterpolationStart, "$\"");
+
+    let mut text: String = String::new();
+    let mut text_start = context.location.clone();
+    loop {
+        let cur = context.peek();
+        let next = context.peek_next();
+        if cur == 0 || cur == '\n' as u32 {
+            context.token_start = start;
+            return Err(unterminated(context));
+        } else if cur == delim {
+            add_text(context, &mut text, &text_start);
+            context.token_start = context.location.clone();
+            context.read();
+            context.add_tok(TokenType::InterpolationEnd, "\"");
+            return Ok(());
+        } else if (cur == '{' as u32 || cur == '}' as u32) && next == cur {
+            text.push(context.read().try_into().unwrap());
+            context.read();
+        } else if cur == '{' as u32 {
+            add_text(context, &mut text, &text_start);
+            read_interpolation(context, delim)?;
+            text_start = context.location.clone();
+        } else if cur == '}' as u32 {
+            return Err(HassiumError::Lex {
+                message: "Unmatched \"}\" in interpolated string, write \"}}\" for a brace!"
+                    .to_string(),
+                location: context.location.clone(),
+            });
+        } else if cur == '\\' as u32 {
+            text.push(read_escape(context)?);
+        } else {
+            text.push(context.read().try_into().unwrap());
+        }
+    }
+}
+
+/// Adds the text read since the last expression of an interpolated string, if any
+fn add_text(context: &mut LexerContext, text: &mut String, start: &Span) {
+    if !text.is_empty() {
+        context.token_start = start.clone();
+        context.add_tok(TokenType::String, text);
+        text.clear();
+    }
+}
+
+/// Reads the `{expression}` or `{expression:spec}` the lexer is at. A `}` or `:`
+/// only ends the expression outside of any brackets it opens
+fn read_interpolation(context: &mut LexerContext, delim: u32) -> Result<(), HassiumError> {
+    let start = context.location.clone();
+    context.token_start = start.clone();
+    context.read();
+    context.add_tok(TokenType::InterpolationOpen, "{");
+    let mut depth = 0;
+    loop {
+        trivia(context)?;
+        let cur = context.peek();
+        // Strings inside brackets may use the same quotes as the interpolated one
+        if cur == 0 || (depth == 0 && cur == delim) {
+            return Err(HassiumError::Lex {
+                message: "Unterminated expression in interpolated string!".to_string(),
+                location: start,
+            });
+        }
+        if depth == 0 && cur == ':' as u32 {
+            context.read();
+            context.token_start = context.location.clone();
+            let mut spec: String = String::new();
+            while ![0, '}' as u32, '\n' as u32, delim].contains(&context.peek()) {
+                spec.push(context.read().try_into().unwrap());
+            }
+            context.add_tok(TokenType::FormatSpec, &spec);
+            continue;
+        }
+        if depth == 0 && cur == '}' as u32 {
+            context.token_start = context.location.clone();
+            context.read();
+            context.add_tok(TokenType::InterpolationClose, "}");
+            return Ok(());
+        }
+        match char::from_u32(cur).unwrap() {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => (),
+        }
+        read_token(context)?;
+    }
+}
+
+/// Reads the escape sequence the backslash the lexer is at starts
+fn read_escape(context: &mut LexerContext) -> Result<char, HassiumError> {
+    let location = context.location.clone();
+    context.read();
+    let escape = context.read();
+    if escape == 0 {
+        return Err(unterminated(context));
+    }
+    let escape: char = escape.try_into().unwrap();
+    Ok(match escape {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        'u' => read_unicode_escape(context)
+            .map_err(|message| HassiumError::Lex { message, location })?,
+        _ => {
+            return Err(HassiumError::Lex {
+                message: format!("Invalid escape sequence \"\\{}\"!", escape),
+                location,
+            })
+        }
+    })
+}
+
+/// Reads the `{1F600}` of a `\u{1F600}` escape, 1 to 6 hex digits naming a code point
+fn read_unicode_escape(context: &mut LexerContext) -> Result<char, String> {
+    if context.peek() != '{' as u32 {
+        return Err("Unicode escapes are written as \"\\u{1F600}\"!".to_string());
+    }
+    context.read();
+    let mut digits: String = String::new();
+    while char::from_u32(context.peek()).is_some_and(|c| c.is_ascii_hexdigit()) {
+        digits.push(context.read().try_into().unwrap());
+    }
+    if context.peek() != '}' as u32 || digits.is_empty() || digits.len() > 6 {
+        return Err("Unicode escapes are written as \"\\u{1F600}\"!".to_string());
+    }
+    context.read();
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("\"\\u{{{}}}\" is not a valid code point!", digits))
+}
+
+fn is_blank(line: &[StrChar]) -> bool {
+    line.iter()
+        .all(|(c, escaped)| !escaped && c.is_whitespace())
+}
+
+/// Drops the line break after the opening quotes and the line the closing quotes
+/// are on when they hold nothing else, then removes the indentation all other
+/// non-blank lines share. Blank lines become empty
+fn strip_indent(chars: Vec<StrChar>) -> Vec<StrChar> {
+    let mut lines: Vec<&[StrChar]> = chars.split(|&(c, escaped)| c == '\n' && !escaped).collect();
+    if lines.len() > 1 && is_blank(lines[0]) {
+        lines.remove(0);
+    }
+    if lines.len() > 1 && is_blank(lines[lines.len() - 1]) {
+        lines.pop();
+    }
+    let indent = lines
+        .iter()
+        .filter(|line| !is_blank(line))
+        .map(|line| {
+            line.iter()
+                .take_while(|(c, escaped)| !escaped && c.is_whitespace())
+                .count()
+        })
+        .min()
+        .unwrap_or(0);
+
+    let mut ret: Vec<StrChar> = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        if index > 0 {
+            ret.push(('\n', false));
+        }
+        if !is_blank(line) {
+            ret.extend_from_slice(&line[indent..]);
+        }
+    }
+    ret
+}
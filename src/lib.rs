@@ -0,0 +1,5 @@
+pub mod emit;
+pub mod error;
+pub mod lexer;
+pub mod parser;
+pub mod runtime;
@@ -1,16 +1,43 @@
-#[macro_use]
-extern crate lazy_static;
+use std::path::Path;
+use std::rc::Rc;
 
-mod emit;
-mod lexer;
-mod parser;
-mod runtime;
+use hassium::error::HassiumError;
+use hassium::{emit, lexer, parser, runtime};
+
+fn run(path: &Path) -> Result<(), HassiumError> {
+    let code = std::fs::read_to_string(path).map_err(|error| {
+        HassiumError::runtime_error(format!("Could not read {}: {}!", path.display(), error))
+    })?;
+    let tokens = lexer::tokenize(code, &path.display().to_string())?;
+    let mut vm = runtime::vm::VMContext::new();
+    // Debug output goes to stderr and is off unless HASSIUM_TRACE is set
+    if std::env::var_os("HASSIUM_TRACE").is_some() {
+        lexer::print_tokens(&tokens);
+        vm.set_tracer(Box::new(runtime::vm::trace::PrintTracer));
+    }
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => vm.set_script_dir(dir),
+        _ => vm.set_script_dir(Path::new(".")),
+    }
+    let module = emit::build_module(
+        parser::parse(&tokens)?,
+        vm.builtin_names(),
+        vm.global_names(),
+    )?;
+    vm.run(Rc::new(module))
+}
 
 fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
-    let mut tokens = lexer::tokenize("println(\"Hello, Hassium!\")".to_string());
-    lexer::print_tokens(&mut tokens);
-    let module = emit::build_module(parser::parse(&mut tokens));
-    let mut vm = runtime::vm::VMContext::new();
-    vm.run(&module);
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: hassium <file.has>");
+            std::process::exit(2);
+        }
+    };
+    if let Err(error) = run(Path::new(&path)) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
 }
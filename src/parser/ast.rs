@@ -1,25 +1,43 @@
+use crate::error::Span;
+
+/// A node of the syntax tree, located at the token it starts with. Operators,
+/// calls, subscripts and attribute accesses are located at their operator so
+/// that errors point at the operation that failed
+#[derive(Clone)]
+pub struct AstNode {
+    pub kind: AstKind,
+    pub span: Span,
+}
+
 #[derive(Clone)]
-pub enum AstNode {
+pub enum AstKind {
     // Statement nodes
     Block {
-        children: Box<Vec<AstNode>>,
+        children: Vec<AstNode>,
+    },
+    Break {
+        label: Option<String>,
     },
-    Break,
     Class {
         name: String,
         extends: Box<Option<AstNode>>,
         body: Box<AstNode>,
     },
-    Continue,
+    Continue {
+        label: Option<String>,
+    },
     Empty,
     For {
+        label: Option<String>,
         initial: Box<AstNode>,
         condition: Box<AstNode>,
         repeated: Box<AstNode>,
         body: Box<AstNode>,
     },
+    /// `var` is a name or a tuple of names that each item is destructured into
     Foreach {
-        var: String,
+        label: Option<String>,
+        var: Box<AstNode>,
         target: Box<AstNode>,
         body: Box<AstNode>,
     },
@@ -34,8 +52,9 @@ pub enum AstNode {
         body: Box<AstNode>,
         else_body: Box<Option<AstNode>>,
     },
+    /// `import a.b.c` binds the module loaded from `a/b/c.has` to `c`
     Import {
-        target: Box<AstNode>,
+        path: Vec<String>,
     },
     Raise {
         value: Box<AstNode>,
@@ -44,14 +63,17 @@ pub enum AstNode {
         value: Box<AstNode>,
     },
     Super {
-        args: Box<Vec<AstNode>>,
+        args: Vec<AstNode>,
     },
+    /// At least one of `catch_body` and `finally_body` is present
     TryCatch {
         try_body: Box<AstNode>,
         value: Option<String>,
-        catch_body: Box<AstNode>,
+        catch_body: Option<Box<AstNode>>,
+        finally_body: Option<Box<AstNode>>,
     },
     While {
+        label: Option<String>,
         condition: Box<AstNode>,
         body: Box<AstNode>,
     },
@@ -72,12 +94,22 @@ pub enum AstNode {
         left: Box<AstNode>,
         right: Box<AstNode>,
     },
+    Dict {
+        entries: Vec<(AstNode, AstNode)>,
+    },
     Id {
         value: String,
     },
+    /// `$"..."`, joined from its parts when evaluated
+    Interpolation {
+        parts: Vec<InterpolationPart>,
+    },
     Invoke {
         target: Box<AstNode>,
-        args: Box<Vec<AstNode>>,
+        args: Vec<AstNode>,
+    },
+    List {
+        items: Vec<AstNode>,
     },
     Number {
         value: f64,
@@ -89,12 +121,31 @@ pub enum AstNode {
         target: Box<AstNode>,
         key: Box<AstNode>,
     },
+    Tuple {
+        items: Vec<AstNode>,
+    },
     UnaryOp {
         op: UnaryOpType,
         target: Box<AstNode>,
     },
 }
 
+impl AstKind {
+    pub fn at(self, span: Span) -> AstNode {
+        AstNode { kind: self, span }
+    }
+}
+
+#[derive(Clone)]
+pub enum InterpolationPart {
+    Text(String),
+    /// A `{value}` or `{value:spec}`
+    Value {
+        value: AstNode,
+        spec: Option<FormatSpec>,
+    },
+}
+
 #[derive(Clone, Debug)]
 pub enum BinOpType {
     Add,
@@ -119,11 +170,89 @@ pub enum UnaryOpType {
     DecrementPre,
     Not,
     IncrementPre,
+    Negate,
 }
 
 #[derive(Clone)]
 pub struct FuncParams {
     pub names: Vec<String>,
     pub variadic: bool,
-    pub types: Vec<Option<AstNode>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// How a `{value:spec}` in an interpolated string is formatted, written as
+/// `[[fill]align][0][width][.precision]`. `<`, `^` and `>` align left, center
+/// and right, numbers align right by default and everything else left. A `0`
+/// before the width pads numbers with zeros after their sign
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatSpec {
+    pub fill: char,
+    pub align: Option<Align>,
+    pub zero: bool,
+    pub width: usize,
+    /// The number of decimals numbers are rounded to
+    pub precision: Option<usize>,
+}
+
+fn align_of(c: char) -> Option<Align> {
+    match c {
+        '<' => Some(Align::Left),
+        '^' => Some(Align::Center),
+        '>' => Some(Align::Right),
+        _ => None,
+    }
+}
+
+impl FormatSpec {
+    pub fn parse(spec: &str) -> Result<FormatSpec, String> {
+        let invalid = || {
+            format!(
+                "Invalid format spec \"{}\", expected [[fill]align][0][width][.precision]!",
+                spec
+            )
+        };
+        let chars: Vec<char> = spec.chars().collect();
+        let (fill, align, mut pos) = match chars.as_slice() {
+            [fill, align, ..] if align_of(*align).is_some() => (*fill, align_of(*align), 2),
+            [align, ..] if align_of(*align).is_some() => (' ', align_of(*align), 1),
+            _ => (' ', None, 0),
+        };
+        let zero = align.is_none() && chars.get(pos) == Some(&'0');
+        if zero {
+            pos += 1;
+        }
+        let read_digits = |pos: &mut usize| -> Result<Option<usize>, String> {
+            let start = *pos;
+            while chars.get(*pos).is_some_and(|c| c.is_ascii_digit()) {
+                *pos += 1;
+            }
+            if start == *pos {
+                return Ok(None);
+            }
+            let digits: String = chars[start..*pos].iter().collect();
+            digits.parse().map(Some).map_err(|_| invalid())
+        };
+        let width = read_digits(&mut pos)?.unwrap_or(0);
+        let mut precision = None;
+        if chars.get(pos) == Some(&'.') {
+            pos += 1;
+            precision = Some(read_digits(&mut pos)?.ok_or_else(invalid)?);
+        }
+        if pos != chars.len() {
+            return Err(invalid());
+        }
+        Ok(FormatSpec {
+            fill,
+            align,
+            zero,
+            width,
+            precision,
+        })
+    }
 }
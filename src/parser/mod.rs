@@ -1,8 +1,11 @@
 pub mod ast;
 
-use crate::lexer::{Token, TokenType};
+use crate::error::{HassiumError, Span};
+use crate::lexer::{NumberKind, Token, TokenType};
 
-pub use self::ast::{AstNode, BinOpType, FuncParams, UnaryOpType};
+pub use self::ast::{
+    AstKind, AstNode, BinOpType, FormatSpec, FuncParams, InterpolationPart, UnaryOpType,
+};
 
 struct ParserContext {
     tokens: Vec<Token>,
@@ -15,7 +18,11 @@ impl ParserContext {
     }
 
     fn match_tok(&self, token_type: TokenType, value: Option<&str>) -> bool {
-        if let Some(current) = self.current() {
+        self.match_tok_at(0, token_type, value)
+    }
+
+    fn match_tok_at(&self, offset: u32, token_type: TokenType, value: Option<&str>) -> bool {
+        if let Some(current) = self.tokens.get((self.pos + offset) as usize) {
             if current.token_type != token_type {
                 return false;
             };
@@ -26,612 +33,1026 @@ impl ParserContext {
         }
     }
 
+    /// Where the current token starts, or the last token if the parser ran
+    /// past the end
+    fn span(&self) -> Span {
+        let token = self.tokens.get(self.pos as usize).or(self.tokens.last());
+        token.unwrap().span.clone()
+    }
+
+    /// Whether the current token is the first on its line
+    fn starts_line(&self) -> bool {
+        match (self.pos.checked_sub(1), self.current()) {
+            (Some(previous), Some(current)) => {
+                self.tokens[previous as usize].span.line < current.span.line
+            }
+            _ => true,
+        }
+    }
+
     fn accept_tok(&mut self, token_type: TokenType, value: Option<&str>) -> bool {
         let ret = self.match_tok(token_type, value);
         if ret {
             self.pos += 1;
         }
-        return ret;
+        ret
     }
 
-    fn expect_tok(&mut self, token_type: TokenType, value: Option<&str>) -> Token {
-        let current_option = self.current();
-        if current_option.is_none() {
-            panic!("Unexpected EOF");
+    /// Like accept_tok, returning where the accepted token starts
+    fn accept_span(&mut self, token_type: TokenType, value: Option<&str>) -> Option<Span> {
+        let span = self.span();
+        match self.accept_tok(token_type, value) {
+            true => Some(span),
+            false => None,
         }
-        let current = current_option.unwrap();
-        if value.is_none() {
-            if current.token_type == token_type {
-                self.pos += 1;
-                current
-            } else {
-                panic!(
-                    "Unexpected {} '{}', expected {}!",
-                    current.token_type, current.value, token_type
-                );
+    }
+
+    fn expect_tok(
+        &mut self,
+        token_type: TokenType,
+        value: Option<&str>,
+    ) -> Result<Token, HassiumError> {
+        let current = match self.current() {
+            Some(current) => current,
+            None => return Err(self.error("Unexpected EOF!".to_string())),
+        };
+        match value {
+            None => {
+                if current.token_type == token_type {
+                    self.pos += 1;
+                    Ok(current)
+                } else {
+                    Err(self.error(format!(
+                        "Unexpected {} '{}', expected {}!",
+                        current.token_type, current.value, token_type
+                    )))
+                }
             }
-        } else {
-            let _value = value.unwrap();
-            if current.token_type == token_type && current.value == _value {
-                self.pos += 1;
-                current
-            } else {
-                panic!(
-                    "Unexpected {} '{}', expected {} '{}'!",
-                    current.token_type, current.value, token_type, _value
-                )
+            Some(_value) => {
+                if current.token_type == token_type && current.value == _value {
+                    self.pos += 1;
+                    Ok(current)
+                } else {
+                    Err(self.error(format!(
+                        "Unexpected {} '{}', expected {} '{}'!",
+                        current.token_type, current.value, token_type, _value
+                    )))
+                }
             }
         }
     }
+
+    /// Builds a syntax error located at the current token, or at the last token
+    /// if the parser ran past the end
+    fn error(&self, message: String) -> HassiumError {
+        HassiumError::Syntax {
+            message,
+            location: Some(self.span()),
+        }
+    }
 }
 
-pub fn parse(tokens: &mut Vec<Token>) -> AstNode {
+pub fn parse(tokens: &[Token]) -> Result<AstNode, HassiumError> {
     let mut context: ParserContext = ParserContext {
         tokens: tokens.to_vec(),
         pos: 0,
     };
+    let span = context.span();
     let mut children: Vec<AstNode> = Vec::new();
-    while !context.match_tok(TokenType::EOF, None) {
-        children.push(parse_statement(&mut context));
+    while !context.match_tok(TokenType::Eof, None) {
+        children.push(parse_statement(&mut context)?);
     }
 
-    AstNode::Block {
-        children: Box::new(children),
-    }
+    Ok(AstKind::Block { children }.at(span))
 }
 
-fn parse_statement(context: &mut ParserContext) -> AstNode {
-    let statement: AstNode = if context.match_tok(TokenType::OpenBrace, None) {
-        parse_block(context)
+fn parse_statement(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    let statement: AstNode = if context.match_tok(TokenType::Id, None)
+        && context.match_tok_at(1, TokenType::Colon, None)
+    {
+        parse_labelled(context)?
+    } else if context.match_tok(TokenType::OpenBrace, None) {
+        parse_block(context)?
     } else if context.match_tok(TokenType::Id, Some("break")) {
-        parse_break(context)
+        parse_break(context)?
     } else if context.match_tok(TokenType::Id, Some("continue")) {
-        parse_continue(context)
+        parse_continue(context)?
     } else if context.match_tok(TokenType::Id, Some("class")) {
-        parse_class(context)
-    } else if context.match_tok(TokenType::Id, Some("continue")) {
-        parse_continue(context)
+        parse_class(context)?
     } else if context.match_tok(TokenType::Id, Some("for")) {
-        parse_for(context)
+        parse_for(context)?
     } else if context.match_tok(TokenType::Id, Some("foreach")) {
-        parse_foreach(context)
+        parse_foreach(context)?
     } else if context.match_tok(TokenType::Id, Some("func")) {
-        parse_func(context)
+        parse_func(context)?
     } else if context.accept_tok(TokenType::Semicolon, None) {
-        AstNode::Empty
+        AstKind::Empty.at(span)
     } else if context.match_tok(TokenType::Id, Some("if")) {
-        parse_if(context)
+        parse_if(context)?
     } else if context.match_tok(TokenType::Id, Some("import")) {
-        parse_import(context)
+        parse_import(context)?
     } else if context.match_tok(TokenType::Id, Some("raise")) {
-        parse_raise(context)
+        parse_raise(context)?
     } else if context.match_tok(TokenType::Id, Some("return")) {
-        parse_return(context)
+        parse_return(context)?
     } else if context.match_tok(TokenType::Id, Some("super")) {
-        parse_super(context)
+        parse_super(context)?
     } else if context.match_tok(TokenType::Id, Some("try")) {
-        parse_try_catch(context)
+        parse_try_catch(context)?
     } else if context.match_tok(TokenType::Id, Some("while")) {
-        parse_while(context)
+        parse_while(context)?
     } else {
-        parse_expression_statement(context)
+        parse_expression_statement(context)?
     };
 
     context.accept_tok(TokenType::Semicolon, None);
 
-    return statement;
+    Ok(statement)
 }
 
-fn parse_block(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::OpenBrace, None);
+fn parse_block(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::OpenBrace, None)?;
     let mut children: Vec<AstNode> = Vec::new();
     while !context.accept_tok(TokenType::CloseBrace, None) {
-        children.push(parse_statement(context));
-    }
-    AstNode::Block {
-        children: Box::new(children),
+        children.push(parse_statement(context)?);
     }
+    Ok(AstKind::Block { children }.at(span))
 }
 
-fn parse_break(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("break"));
-    AstNode::Break
+fn parse_break(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    let token = context.expect_tok(TokenType::Id, Some("break"))?;
+    Ok(AstKind::Break {
+        label: parse_jump_label(context, &token),
+    }
+    .at(span))
 }
 
-fn parse_class(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("class"));
-    let name: String = context.expect_tok(TokenType::Id, None).value.clone();
+fn parse_class(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("class"))?;
+    let name: String = context.expect_tok(TokenType::Id, None)?.value.clone();
     let extends: Option<AstNode> = if context.accept_tok(TokenType::Id, Some("extends")) {
-        Some(parse_expression(context))
+        Some(parse_expression(context)?)
     } else {
         None
     };
-    let body = parse_statement(context);
+    let body = parse_statement(context)?;
 
-    AstNode::Class {
+    Ok(AstKind::Class {
         name,
         extends: Box::new(extends),
         body: Box::new(body),
     }
+    .at(span))
+}
+
+fn parse_continue(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    let token = context.expect_tok(TokenType::Id, Some("continue"))?;
+    Ok(AstKind::Continue {
+        label: parse_jump_label(context, &token),
+    }
+    .at(span))
 }
 
-fn parse_continue(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("continue"));
-    AstNode::Continue
+/// The loop label after `break` or `continue`, it has to be on the same line so
+/// that a statement starting on the next line is not mistaken for one
+fn parse_jump_label(context: &mut ParserContext, keyword: &Token) -> Option<String> {
+    match context.current() {
+        Some(token)
+            if token.token_type == TokenType::Id && token.span.line == keyword.span.line =>
+        {
+            context.pos += 1;
+            Some(token.value)
+        }
+        _ => None,
+    }
 }
 
-fn parse_for(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("for"));
+fn parse_for(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("for"))?;
     let using_parens = context.accept_tok(TokenType::OpenParen, None);
-    let initial = parse_expression(context);
+    let initial = parse_expression(context)?;
     context.accept_tok(TokenType::Semicolon, None);
-    let condition: AstNode = parse_expression(context);
+    let condition: AstNode = parse_expression(context)?;
     context.accept_tok(TokenType::Semicolon, None);
-    let repeated: AstNode = parse_expression(context);
+    let repeated: AstNode = parse_expression(context)?;
     if using_parens {
-        context.expect_tok(TokenType::CloseParen, None);
+        context.expect_tok(TokenType::CloseParen, None)?;
     }
-    let body: AstNode = parse_statement(context);
+    let body: AstNode = parse_statement(context)?;
 
-    AstNode::For {
+    Ok(AstKind::For {
+        label: None,
         initial: Box::new(initial),
         condition: Box::new(condition),
         repeated: Box::new(repeated),
         body: Box::new(body),
     }
+    .at(span))
 }
 
-fn parse_foreach(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("foreach"));
+fn parse_foreach(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("foreach"))?;
     let using_parens: bool = context.accept_tok(TokenType::OpenParen, None);
-    let var: String = context.expect_tok(TokenType::Id, None).value.clone();
-    context.expect_tok(TokenType::Id, Some("in"));
-    let target: AstNode = parse_expression(context);
+    let var: AstNode = parse_pattern(context)?;
+    context.expect_tok(TokenType::Id, Some("in"))?;
+    let target: AstNode = parse_expression(context)?;
     if using_parens {
-        context.expect_tok(TokenType::CloseParen, None);
+        context.expect_tok(TokenType::CloseParen, None)?;
     }
-    let body: AstNode = parse_statement(context);
+    let body: AstNode = parse_statement(context)?;
 
-    AstNode::Foreach {
-        var,
+    Ok(AstKind::Foreach {
+        label: None,
+        var: Box::new(var),
         target: Box::new(target),
         body: Box::new(body),
     }
+    .at(span))
+}
+
+/// A name, or a parenthesized tuple of patterns to destructure a value into
+fn parse_pattern(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    if !context.accept_tok(TokenType::OpenParen, None) {
+        return Ok(AstKind::Id {
+            value: context.expect_tok(TokenType::Id, None)?.value.clone(),
+        }
+        .at(span));
+    }
+    let mut items: Vec<AstNode> = Vec::new();
+    while !context.accept_tok(TokenType::CloseParen, None) {
+        items.push(parse_pattern(context)?);
+        if !context.match_tok(TokenType::CloseParen, None) {
+            context.expect_tok(TokenType::Comma, None)?;
+        }
+    }
+    Ok(AstKind::Tuple { items }.at(span))
 }
 
-fn parse_func(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("func"));
-    let name: String = context.expect_tok(TokenType::Id, None).value.clone();
-    let params: FuncParams = parse_func_params(context);
+fn parse_func(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("func"))?;
+    let name: String = context.expect_tok(TokenType::Id, None)?.value.clone();
+    let params: FuncParams = parse_func_params(context)?;
     let return_type: Option<AstNode> = if context.accept_tok(TokenType::Colon, None) {
-        Some(parse_expression(context))
+        Some(parse_expression(context)?)
     } else {
         None
     };
-    let body: AstNode = parse_statement(context);
+    let body: AstNode = parse_statement(context)?;
 
-    AstNode::Func {
+    Ok(AstKind::Func {
         name,
         params,
         return_type: Box::new(return_type),
         body: Box::new(body),
     }
+    .at(span))
 }
 
-fn parse_func_params(context: &mut ParserContext) -> FuncParams {
-    context.expect_tok(TokenType::OpenParen, None);
+fn parse_func_params(context: &mut ParserContext) -> Result<FuncParams, HassiumError> {
+    context.expect_tok(TokenType::OpenParen, None)?;
     let mut names: Vec<String> = Vec::new();
-    let mut types: Vec<Option<AstNode>> = Vec::new();
     let mut variadic: bool = false;
     while !context.accept_tok(TokenType::CloseParen, None) {
         if context.accept_tok(TokenType::Variadic, None) {
             variadic = true;
-            context.expect_tok(TokenType::CloseParen, None);
+            context.expect_tok(TokenType::CloseParen, None)?;
             break;
         }
-        names.push(context.expect_tok(TokenType::Id, None).value.clone());
-        types.push(if context.accept_tok(TokenType::Colon, None) {
-            Some(parse_expression(context))
-        } else {
-            None
-        });
-        // If the next token is not a comma or a cparen, fail by expecting the comma
-        if !context.match_tok(TokenType::Comma, None)
-            && !context.match_tok(TokenType::CloseParen, None)
-        {
-            context.expect_tok(TokenType::Comma, None);
+        names.push(context.expect_tok(TokenType::Id, None)?.value.clone());
+        // Parameter types are accepted for documentation but not checked
+        if context.accept_tok(TokenType::Colon, None) {
+            parse_expression(context)?;
+        }
+        // If the next token is not a cparen, fail by expecting the comma
+        if !context.match_tok(TokenType::CloseParen, None) {
+            context.expect_tok(TokenType::Comma, None)?;
         }
     }
 
-    FuncParams {
-        names,
-        types,
-        variadic,
-    }
+    Ok(FuncParams { names, variadic })
 }
 
-fn parse_if(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("if"));
+fn parse_if(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("if"))?;
     let using_parens: bool = context.accept_tok(TokenType::OpenParen, None);
-    let predicate = parse_expression(context);
+    let predicate = parse_expression(context)?;
     if using_parens {
-        context.expect_tok(TokenType::CloseParen, None);
+        context.expect_tok(TokenType::CloseParen, None)?;
     }
-    let body = parse_statement(context);
+    let body = parse_statement(context)?;
     let else_body: Option<AstNode> = if context.accept_tok(TokenType::Id, Some("else")) {
-        Some(parse_statement(context))
+        Some(parse_statement(context)?)
     } else {
         None
     };
 
-    AstNode::If {
+    Ok(AstKind::If {
         predicate: Box::new(predicate),
         body: Box::new(body),
         else_body: Box::new(else_body),
     }
+    .at(span))
 }
 
-fn parse_import(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("import"));
-    let target = parse_expression(context);
-    AstNode::Import {
-        target: Box::new(target),
+fn parse_import(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("import"))?;
+    let mut path: Vec<String> = vec![context.expect_tok(TokenType::Id, None)?.value.clone()];
+    while context.accept_tok(TokenType::Dot, None) {
+        path.push(context.expect_tok(TokenType::Id, None)?.value.clone());
     }
+    Ok(AstKind::Import { path }.at(span))
 }
 
-fn parse_raise(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("raise"));
-    let value = parse_expression(context);
-    AstNode::Raise {
+/// A loop prefixed with `name:` so that nested loops can break out of it
+fn parse_labelled(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let name: String = context.expect_tok(TokenType::Id, None)?.value.clone();
+    context.expect_tok(TokenType::Colon, None)?;
+    let mut statement = if context.match_tok(TokenType::Id, Some("for")) {
+        parse_for(context)?
+    } else if context.match_tok(TokenType::Id, Some("foreach")) {
+        parse_foreach(context)?
+    } else if context.match_tok(TokenType::Id, Some("while")) {
+        parse_while(context)?
+    } else {
+        return Err(context.error(format!("Label {} must be followed by a loop!", name)));
+    };
+    match &mut statement.kind {
+        AstKind::For { label, .. }
+        | AstKind::Foreach { label, .. }
+        | AstKind::While { label, .. } => *label = Some(name),
+        _ => (),
+    }
+    Ok(statement)
+}
+
+fn parse_raise(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("raise"))?;
+    let value = parse_expression(context)?;
+    Ok(AstKind::Raise {
         value: Box::new(value),
     }
+    .at(span))
 }
 
-fn parse_return(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("return"));
-    let value: AstNode = parse_expression(context);
-    AstNode::Return {
+fn parse_return(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("return"))?;
+    let value: AstNode = parse_expression(context)?;
+    Ok(AstKind::Return {
         value: Box::new(value),
     }
+    .at(span))
 }
 
-fn parse_super(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("super"));
-    context.expect_tok(TokenType::OpenParen, None);
+fn parse_super(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("super"))?;
+    context.expect_tok(TokenType::OpenParen, None)?;
     let mut args: Vec<AstNode> = Vec::new();
     while !context.accept_tok(TokenType::CloseParen, None) {
-        args.push(parse_expression(context));
+        args.push(parse_expression(context)?);
         if !context.match_tok(TokenType::CloseParen, None) {
-            context.expect_tok(TokenType::Comma, None);
+            context.expect_tok(TokenType::Comma, None)?;
         }
     }
 
-    AstNode::Super {
-        args: Box::new(args),
-    }
+    Ok(AstKind::Super { args }.at(span))
 }
 
-fn parse_try_catch(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("try"));
-    let try_body: AstNode = parse_statement(context);
-    context.expect_tok(TokenType::Id, Some("catch"));
-    let value: Option<String> = if context.accept_tok(TokenType::OpenParen, None) {
-        Some(context.expect_tok(TokenType::Id, None).value.clone())
+fn parse_try_catch(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("try"))?;
+    let try_body: AstNode = parse_statement(context)?;
+    let mut value: Option<String> = None;
+    let mut catch_body: Option<Box<AstNode>> = None;
+    if !context.match_tok(TokenType::Id, Some("finally")) {
+        context.expect_tok(TokenType::Id, Some("catch"))?;
+        if context.accept_tok(TokenType::OpenParen, None) {
+            value = Some(context.expect_tok(TokenType::Id, None)?.value.clone());
+            context.expect_tok(TokenType::CloseParen, None)?;
+        }
+        catch_body = Some(Box::new(parse_statement(context)?));
+    }
+    let finally_body: Option<Box<AstNode>> = if context.accept_tok(TokenType::Id, Some("finally")) {
+        Some(Box::new(parse_statement(context)?))
     } else {
         None
     };
-    let catch_body: AstNode = parse_statement(context);
 
-    AstNode::TryCatch {
+    Ok(AstKind::TryCatch {
         try_body: Box::new(try_body),
         value,
-        catch_body: Box::new(catch_body),
+        catch_body,
+        finally_body,
     }
+    .at(span))
 }
 
-fn parse_while(context: &mut ParserContext) -> AstNode {
-    context.expect_tok(TokenType::Id, Some("while"));
+fn parse_while(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    context.expect_tok(TokenType::Id, Some("while"))?;
     let using_parens: bool = context.accept_tok(TokenType::OpenParen, None);
-    let condition: AstNode = parse_expression(context);
+    let condition: AstNode = parse_expression(context)?;
     if using_parens {
-        context.expect_tok(TokenType::CloseParen, None);
+        context.expect_tok(TokenType::CloseParen, None)?;
     }
-    let body: AstNode = parse_statement(context);
+    let body: AstNode = parse_statement(context)?;
 
-    AstNode::While {
+    Ok(AstKind::While {
+        label: None,
         condition: Box::new(condition),
         body: Box::new(body),
     }
+    .at(span))
 }
 
-fn parse_expression_statement(context: &mut ParserContext) -> AstNode {
-    let expression = parse_expression(context);
+fn parse_expression_statement(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let expression = parse_expression(context)?;
     context.accept_tok(TokenType::Semicolon, None);
-    AstNode::ExpressionStatement {
+    let span = expression.span.clone();
+    Ok(AstKind::ExpressionStatement {
         expression: Box::new(expression),
     }
+    .at(span))
 }
 
-fn parse_expression(context: &mut ParserContext) -> AstNode {
+fn parse_expression(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
     parse_assign(context)
 }
 
-fn parse_assign(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_or(context);
+fn parse_assign(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let left: AstNode = parse_or(context)?;
     if context.match_tok(TokenType::Assign, None) {
-        let op_str: String = context.expect_tok(TokenType::Assign, None).value.clone();
+        let span = context.span();
+        let op_str: String = context.expect_tok(TokenType::Assign, None)?.value.clone();
         if op_str.eq("=") {
-            return AstNode::Assign {
+            Ok(AstKind::Assign {
                 left: Box::new(left),
-                right: Box::new(parse_assign(context)),
-            };
+                right: Box::new(parse_assign(context)?),
+            }
+            .at(span))
         } else {
             let bin_op_type = match op_str.as_str() {
                 "+=" => BinOpType::Add,
                 "-=" => BinOpType::Subtract,
                 "*=" => BinOpType::Multiply,
+                "/=" => BinOpType::Divide,
                 "%=" => BinOpType::Modulus,
                 "&=" => BinOpType::BitwiseAnd,
                 "|=" => BinOpType::BitwiseOr,
                 "^=" => BinOpType::Xor,
-                _ => panic!("Unknown assignment op {}", op_str),
+                _ => return Err(context.error(format!("Unknown assignment op {}!", op_str))),
             };
             let left_clone: AstNode = left.clone();
-            return AstNode::Assign {
+            Ok(AstKind::Assign {
                 left: Box::new(left),
-                right: Box::new(AstNode::BinOp {
-                    op: bin_op_type,
-                    left: Box::new(left_clone),
-                    right: Box::new(parse_assign(context)),
-                }),
-            };
+                right: Box::new(
+                    AstKind::BinOp {
+                        op: bin_op_type,
+                        left: Box::new(left_clone),
+                        right: Box::new(parse_assign(context)?),
+                    }
+                    .at(span.clone()),
+                ),
+            }
+            .at(span))
         }
     } else {
-        return left;
+        Ok(left)
     }
 }
 
-fn parse_or(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_and(context);
-    return if context.accept_tok(TokenType::Op, Some("||")) {
-        AstNode::BinOp {
+fn parse_or(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_and(context)?;
+    while let Some(span) = context.accept_span(TokenType::Op, Some("||")) {
+        left = AstKind::BinOp {
             op: BinOpType::Or,
             left: Box::new(left),
-            right: Box::new(parse_or(context)),
+            right: Box::new(parse_and(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
+    Ok(left)
 }
 
-fn parse_and(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_bitwise_or(context);
-    return if context.accept_tok(TokenType::Op, Some("&&")) {
-        AstNode::BinOp {
+fn parse_and(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_bitwise_or(context)?;
+    while let Some(span) = context.accept_span(TokenType::Op, Some("&&")) {
+        left = AstKind::BinOp {
             op: BinOpType::And,
             left: Box::new(left),
-            right: Box::new(parse_or(context)),
+            right: Box::new(parse_bitwise_or(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
+    Ok(left)
 }
 
-fn parse_bitwise_or(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_xor(context);
-    return if context.accept_tok(TokenType::Op, Some("|")) {
-        AstNode::BinOp {
+fn parse_bitwise_or(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_xor(context)?;
+    while let Some(span) = context.accept_span(TokenType::Op, Some("|")) {
+        left = AstKind::BinOp {
             op: BinOpType::BitwiseOr,
             left: Box::new(left),
-            right: Box::new(parse_or(context)),
+            right: Box::new(parse_xor(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
+    Ok(left)
 }
 
-fn parse_xor(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_bitwise_and(context);
-    return if context.accept_tok(TokenType::Op, Some("^")) {
-        AstNode::BinOp {
+fn parse_xor(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_bitwise_and(context)?;
+    while let Some(span) = context.accept_span(TokenType::Op, Some("^")) {
+        left = AstKind::BinOp {
             op: BinOpType::Xor,
             left: Box::new(left),
-            right: Box::new(parse_or(context)),
+            right: Box::new(parse_bitwise_and(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
+    Ok(left)
 }
 
-fn parse_bitwise_and(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_equality(context);
-    return if context.accept_tok(TokenType::Op, Some("&")) {
-        AstNode::BinOp {
+fn parse_bitwise_and(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_equality(context)?;
+    while let Some(span) = context.accept_span(TokenType::Op, Some("&")) {
+        left = AstKind::BinOp {
             op: BinOpType::BitwiseAnd,
             left: Box::new(left),
-            right: Box::new(parse_or(context)),
+            right: Box::new(parse_equality(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
+    Ok(left)
 }
 
-fn parse_equality(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_comparison(context);
-    return if context.accept_tok(TokenType::Op, Some("==")) {
-        AstNode::BinOp {
-            op: BinOpType::EqualTo,
-            left: Box::new(left),
-            right: Box::new(parse_comparison(context)),
-        }
-    } else if context.accept_tok(TokenType::Op, Some("!=")) {
-        AstNode::UnaryOp {
-            op: UnaryOpType::Not,
-            target: Box::new(AstNode::BinOp {
+fn parse_equality(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_comparison(context)?;
+    loop {
+        let span = context.span();
+        left = if context.accept_tok(TokenType::Op, Some("==")) {
+            AstKind::BinOp {
                 op: BinOpType::EqualTo,
                 left: Box::new(left),
-                right: Box::new(parse_equality(context)),
-            }),
-        }
-    } else {
-        left
-    };
+                right: Box::new(parse_comparison(context)?),
+            }
+            .at(span)
+        } else if context.accept_tok(TokenType::Op, Some("!=")) {
+            AstKind::UnaryOp {
+                op: UnaryOpType::Not,
+                target: Box::new(
+                    AstKind::BinOp {
+                        op: BinOpType::EqualTo,
+                        left: Box::new(left),
+                        right: Box::new(parse_comparison(context)?),
+                    }
+                    .at(span.clone()),
+                ),
+            }
+            .at(span)
+        } else {
+            return Ok(left);
+        };
+    }
 }
 
-fn parse_comparison(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_additive(context);
-    return if context.accept_tok(TokenType::Op, Some(">")) {
-        AstNode::BinOp {
-            op: BinOpType::GreaterThan,
-            left: Box::new(left),
-            right: Box::new(parse_comparison(context)),
-        }
-    } else if context.accept_tok(TokenType::Op, Some(">=")) {
-        AstNode::BinOp {
-            op: BinOpType::GreaterThanOrEqual,
-            left: Box::new(left),
-            right: Box::new(parse_comparison(context)),
-        }
-    } else if context.accept_tok(TokenType::Op, Some("<")) {
-        AstNode::BinOp {
-            op: BinOpType::LesserThan,
-            left: Box::new(left),
-            right: Box::new(parse_comparison(context)),
-        }
-    } else if context.accept_tok(TokenType::Op, Some("<=")) {
-        AstNode::BinOp {
-            op: BinOpType::LesserThanOrEqual,
+fn parse_comparison(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_additive(context)?;
+    loop {
+        let span = context.span();
+        let op = if context.accept_tok(TokenType::Op, Some(">")) {
+            BinOpType::GreaterThan
+        } else if context.accept_tok(TokenType::Op, Some(">=")) {
+            BinOpType::GreaterThanOrEqual
+        } else if context.accept_tok(TokenType::Op, Some("<")) {
+            BinOpType::LesserThan
+        } else if context.accept_tok(TokenType::Op, Some("<=")) {
+            BinOpType::LesserThanOrEqual
+        } else {
+            return Ok(left);
+        };
+        left = AstKind::BinOp {
+            op,
             left: Box::new(left),
-            right: Box::new(parse_comparison(context)),
+            right: Box::new(parse_additive(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
 }
 
-fn parse_additive(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_multiplicative(context);
-    return if context.accept_tok(TokenType::Op, Some("+")) {
-        AstNode::BinOp {
-            op: BinOpType::Add,
-            left: Box::new(left),
-            right: Box::new(parse_additive(context)),
-        }
-    } else if context.accept_tok(TokenType::Op, Some("-")) {
-        AstNode::BinOp {
-            op: BinOpType::Subtract,
+fn parse_additive(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_multiplicative(context)?;
+    loop {
+        let span = context.span();
+        let op = if context.accept_tok(TokenType::Op, Some("+")) {
+            BinOpType::Add
+        } else if context.accept_tok(TokenType::Op, Some("-")) {
+            BinOpType::Subtract
+        } else {
+            return Ok(left);
+        };
+        left = AstKind::BinOp {
+            op,
             left: Box::new(left),
-            right: Box::new(parse_additive(context)),
+            right: Box::new(parse_multiplicative(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
 }
 
-fn parse_multiplicative(context: &mut ParserContext) -> AstNode {
-    let left: AstNode = parse_unary(context);
-    return if context.accept_tok(TokenType::Op, Some("/")) {
-        AstNode::BinOp {
-            op: BinOpType::Divide,
-            left: Box::new(left),
-            right: Box::new(parse_additive(context)),
-        }
-    } else if context.accept_tok(TokenType::Op, Some("%")) {
-        AstNode::BinOp {
-            op: BinOpType::Multiply,
-            left: Box::new(left),
-            right: Box::new(parse_multiplicative(context)),
-        }
-    } else if context.accept_tok(TokenType::Op, Some("*")) {
-        AstNode::BinOp {
-            op: BinOpType::Multiply,
+fn parse_multiplicative(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let mut left: AstNode = parse_unary(context)?;
+    loop {
+        let span = context.span();
+        let op = if context.accept_tok(TokenType::Op, Some("/")) {
+            BinOpType::Divide
+        } else if context.accept_tok(TokenType::Op, Some("%")) {
+            BinOpType::Modulus
+        } else if context.accept_tok(TokenType::Op, Some("*")) {
+            BinOpType::Multiply
+        } else {
+            return Ok(left);
+        };
+        left = AstKind::BinOp {
+            op,
             left: Box::new(left),
-            right: Box::new(parse_multiplicative(context)),
+            right: Box::new(parse_unary(context)?),
         }
-    } else {
-        left
-    };
+        .at(span);
+    }
 }
 
-fn parse_unary(context: &mut ParserContext) -> AstNode {
-    return if context.accept_tok(TokenType::Op, Some("!")) {
-        AstNode::UnaryOp {
+fn parse_unary(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    Ok(if context.accept_tok(TokenType::Op, Some("!")) {
+        AstKind::UnaryOp {
             op: UnaryOpType::Not,
-            target: Box::new(parse_unary(context)),
+            target: Box::new(parse_unary(context)?),
+        }
+        .at(span)
+    } else if context.accept_tok(TokenType::Op, Some("-")) {
+        AstKind::UnaryOp {
+            op: UnaryOpType::Negate,
+            target: Box::new(parse_unary(context)?),
         }
+        .at(span)
     } else if context.accept_tok(TokenType::Op, Some("--")) {
-        AstNode::UnaryOp {
+        AstKind::UnaryOp {
             op: UnaryOpType::DecrementPre,
-            target: Box::new(parse_unary(context)),
+            target: Box::new(parse_unary(context)?),
         }
+        .at(span)
     } else if context.accept_tok(TokenType::Op, Some("++")) {
-        AstNode::UnaryOp {
+        AstKind::UnaryOp {
             op: UnaryOpType::IncrementPre,
-            target: Box::new(parse_unary(context)),
+            target: Box::new(parse_unary(context)?),
         }
+        .at(span)
     } else {
-        parse_access(context, None)
-    };
+        parse_access(context, None)?
+    })
 }
 
-fn parse_access(context: &mut ParserContext, left: Option<AstNode>) -> AstNode {
-    let _left = left.unwrap_or(parse_term(context));
-    return if context.accept_tok(TokenType::OpenParen, None) {
+fn parse_access(
+    context: &mut ParserContext,
+    left: Option<AstNode>,
+) -> Result<AstNode, HassiumError> {
+    let _left = match left {
+        Some(left) => left,
+        None => parse_term(context)?,
+    };
+    // Semicolons are optional, so a parenthesis or square bracket starting a
+    // line begins a new statement, such as `(x, y) = point`, rather than
+    // calling or indexing the previous line. A call or subscript has to open on
+    // the line its target ends on, `f\n(1)` is `f` followed by `(1)`
+    if (context.match_tok(TokenType::OpenParen, None)
+        || context.match_tok(TokenType::OpenSquare, None))
+        && context.starts_line()
+    {
+        return Ok(_left);
+    }
+    let span = context.span();
+    Ok(if context.accept_tok(TokenType::OpenParen, None) {
         let mut args: Vec<AstNode> = Vec::new();
         while !context.accept_tok(TokenType::CloseParen, None) {
-            args.push(parse_expression(context))
-        }
-        AstNode::Invoke {
-            target: Box::new(_left),
-            args: Box::new(args),
+            args.push(parse_expression(context)?);
+            if !context.match_tok(TokenType::CloseParen, None) {
+                context.expect_tok(TokenType::Comma, None)?;
+            }
         }
+        parse_access(
+            context,
+            Some(
+                AstKind::Invoke {
+                    target: Box::new(_left),
+                    args,
+                }
+                .at(span),
+            ),
+        )?
     } else if context.accept_tok(TokenType::OpenSquare, None) {
-        let key: AstNode = parse_expression(context);
-        context.expect_tok(TokenType::CloseSquare, None);
-        AstNode::Subscript {
-            target: Box::new(_left),
-            key: Box::new(key),
-        }
+        let key: AstNode = parse_expression(context)?;
+        context.expect_tok(TokenType::CloseSquare, None)?;
+        parse_access(
+            context,
+            Some(
+                AstKind::Subscript {
+                    target: Box::new(_left),
+                    key: Box::new(key),
+                }
+                .at(span),
+            ),
+        )?
     } else if context.accept_tok(TokenType::Dot, None) {
-        let attrib = context.expect_tok(TokenType::Id, None).value.clone();
+        let attrib = context.expect_tok(TokenType::Id, None)?.value.clone();
         parse_access(
             context,
-            Some(AstNode::AttribAccess {
-                target: Box::new(_left),
-                attrib,
-            }),
-        )
+            Some(
+                AstKind::AttribAccess {
+                    target: Box::new(_left),
+                    attrib,
+                }
+                .at(span),
+            ),
+        )?
     } else {
         _left
+    })
+}
+
+/// The value of a number literal the lexer accepted. Integers must fit in 64
+/// bits and be exactly representable as a number, they are never rounded
+fn number_value(literal: &str, kind: NumberKind) -> Result<f64, String> {
+    let too_large = || format!("Number {} is too large!", literal);
+    let (digits, radix) = match literal.get(..2) {
+        Some("0x" | "0X") => (&literal[2..], 16),
+        Some("0b" | "0B") => (&literal[2..], 2),
+        Some("0o" | "0O") => (&literal[2..], 8),
+        _ if kind == NumberKind::Float => {
+            return literal
+                .parse()
+                .ok()
+                .filter(|value: &f64| value.is_finite())
+                .ok_or_else(too_large)
+        }
+        _ => (literal, 10),
     };
+    let value = u64::from_str_radix(digits, radix).map_err(|_| too_large())?;
+    // A number has 53 bits of precision, counted from the highest set bit to
+    // the lowest
+    if value != 0 && 64 - value.leading_zeros() - value.trailing_zeros() > 53 {
+        return Err(format!(
+            "Integer {} cannot be represented exactly, the closest number is {}!",
+            literal, value as f64
+        ));
+    }
+    Ok(value as f64)
 }
 
-fn parse_term(context: &mut ParserContext) -> AstNode {
-    return if context.match_tok(TokenType::Id, None) {
-        AstNode::Id {
-            value: context.expect_tok(TokenType::Id, None).value.clone(),
+fn parse_term(context: &mut ParserContext) -> Result<AstNode, HassiumError> {
+    let span = context.span();
+    Ok(if context.match_tok(TokenType::Id, None) {
+        AstKind::Id {
+            value: context.expect_tok(TokenType::Id, None)?.value.clone(),
         }
-    } else if context.match_tok(TokenType::Number, None) {
-        AstNode::Number {
-            value: context
-                .expect_tok(TokenType::Number, None)
-                .value
-                .parse()
-                .unwrap(),
+        .at(span)
+    } else if let Some(TokenType::Number(kind)) = context.current().map(|token| token.token_type) {
+        let token = context.expect_tok(TokenType::Number(kind), None)?;
+        match number_value(&token.value, kind) {
+            Ok(value) => AstKind::Number { value }.at(span),
+            Err(message) => {
+                return Err(HassiumError::Syntax {
+                    message,
+                    location: Some(token.span),
+                })
+            }
         }
     } else if context.match_tok(TokenType::String, None) {
-        AstNode::String {
-            value: context.expect_tok(TokenType::String, None).value.clone(),
+        AstKind::String {
+            value: context.expect_tok(TokenType::String, None)?.value.clone(),
+        }
+        .at(span)
+    } else if context.accept_tok(TokenType::InterpolationStart, None) {
+        let mut parts: Vec<InterpolationPart> = Vec::new();
+        while !context.accept_tok(TokenType::InterpolationEnd, None) {
+            if context.match_tok(TokenType::String, None) {
+                let text = context.expect_tok(TokenType::String, None)?.value;
+                parts.push(InterpolationPart::Text(text));
+                continue;
+            }
+            context.expect_tok(TokenType::InterpolationOpen, None)?;
+            let value = parse_expression(context)?;
+            let mut spec = None;
+            if context.match_tok(TokenType::FormatSpec, None) {
+                let token = context.expect_tok(TokenType::FormatSpec, None)?;
+                spec = Some(FormatSpec::parse(&token.value).map_err(|message| {
+                    HassiumError::Syntax {
+                        message,
+                        location: Some(token.span),
+                    }
+                })?);
+            }
+            context.expect_tok(TokenType::InterpolationClose, None)?;
+            parts.push(InterpolationPart::Value { value, spec });
         }
+        AstKind::Interpolation { parts }.at(span)
     } else if context.accept_tok(TokenType::OpenParen, None) {
-        let expression = parse_expression(context);
-        context.expect_tok(TokenType::CloseParen, None);
-        expression
+        // A comma makes a tuple, `()` is the empty tuple and `(x,)` has one item
+        if context.accept_tok(TokenType::CloseParen, None) {
+            return Ok(AstKind::Tuple { items: Vec::new() }.at(span));
+        }
+        let expression = parse_expression(context)?;
+        if context.accept_tok(TokenType::CloseParen, None) {
+            return Ok(expression);
+        }
+        let mut items: Vec<AstNode> = vec![expression];
+        context.expect_tok(TokenType::Comma, None)?;
+        while !context.accept_tok(TokenType::CloseParen, None) {
+            items.push(parse_expression(context)?);
+            if !context.match_tok(TokenType::CloseParen, None) {
+                context.expect_tok(TokenType::Comma, None)?;
+            }
+        }
+        AstKind::Tuple { items }.at(span)
+    } else if context.accept_tok(TokenType::OpenSquare, None) {
+        let mut items: Vec<AstNode> = Vec::new();
+        while !context.accept_tok(TokenType::CloseSquare, None) {
+            items.push(parse_expression(context)?);
+            if !context.match_tok(TokenType::CloseSquare, None) {
+                context.expect_tok(TokenType::Comma, None)?;
+            }
+        }
+        AstKind::List { items }.at(span)
+    } else if context.accept_tok(TokenType::OpenBrace, None) {
+        // Statements starting with a brace are blocks, where an expression is
+        // expected a brace always starts a dict
+        let mut entries: Vec<(AstNode, AstNode)> = Vec::new();
+        while !context.accept_tok(TokenType::CloseBrace, None) {
+            let key = parse_expression(context)?;
+            context.expect_tok(TokenType::Colon, None)?;
+            entries.push((key, parse_expression(context)?));
+            if !context.match_tok(TokenType::CloseBrace, None) {
+                context.expect_tok(TokenType::Comma, None)?;
+            }
+        }
+        AstKind::Dict { entries }.at(span)
     } else {
-        panic!(
-            "Unexpected {} {}",
-            context.current().unwrap().token_type,
-            context.current().unwrap().value
-        )
-    };
+        return Err(match context.current() {
+            Some(current) if current.token_type != TokenType::Eof => context.error(format!(
+                "Unexpected {} {}!",
+                current.token_type, current.value
+            )),
+            _ => context.error("Unexpected EOF!".to_string()),
+        });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    fn parse_error(code: &str) -> HassiumError {
+        let tokens = tokenize(code.to_string(), "test").unwrap();
+        parse(&tokens).err().expect("expected a syntax error")
+    }
+
+    #[test]
+    fn unexpected_tokens_are_syntax_errors() {
+        let error = parse_error("x = 1;\ny = (2 + 3;");
+        assert!(matches!(error, HassiumError::Syntax { .. }));
+        assert_eq!(
+            error.to_string(),
+            "test:2:11: SyntaxError: Unexpected Semicolon ';', expected Comma!"
+        );
+    }
+
+    #[test]
+    fn integers_that_would_be_rounded_are_rejected() {
+        let error = parse_error("x = 0x20000000000001;");
+        assert_eq!(
+            error.to_string(),
+            "test:1:5: SyntaxError: Integer 0x20000000000001 cannot be represented exactly, \
+             the closest number is 9007199254740992!"
+        );
+        let tokens = tokenize(
+            "x = 0x1FFFFFFFFFFFFF + 0x8000000000000000;".to_string(),
+            "test",
+        );
+        assert!(parse(&tokens.unwrap()).is_ok());
+    }
+
+    #[test]
+    fn inexact_integers_are_rejected_in_every_radix() {
+        for (literal, closest) in [
+            ("9007199254740993", "9007199254740992"),
+            ("0o400000000000000001", "9007199254740992"),
+            (
+                "0b1000000000000000000000000000000000000000000000000000001",
+                "18014398509481984",
+            ),
+            ("0xFFFFFFFFFFFFFFFF", "18446744073709552000"),
+        ] {
+            let error = parse_error(&format!("x = {};", literal));
+            assert_eq!(
+                error.message(),
+                format!(
+                    "Integer {} cannot be represented exactly, the closest number is {}!",
+                    literal, closest
+                )
+            );
+        }
+        let error = parse_error("x = 0x1_0000_0000_0000_0000;");
+        assert_eq!(error.message(), "Number 0x10000000000000000 is too large!");
+    }
+
+    #[test]
+    fn numbers_past_the_largest_are_rejected() {
+        let error = parse_error("x = 99999999999999999999;");
+        assert_eq!(
+            error.to_string(),
+            "test:1:5: SyntaxError: Number 99999999999999999999 is too large!"
+        );
+        let error = parse_error("x = 1e999;");
+        assert_eq!(
+            error.to_string(),
+            "test:1:5: SyntaxError: Number 1e999 is too large!"
+        );
+    }
+
+    #[test]
+    fn format_specs_are_parsed_with_the_string() {
+        let spec = FormatSpec::parse("*^08.2").unwrap();
+        assert_eq!(spec.fill, '*');
+        assert_eq!(spec.align, Some(ast::Align::Center));
+        assert_eq!((spec.zero, spec.width, spec.precision), (false, 8, Some(2)));
+        let error = parse_error("x = $\"{1:8x}\";");
+        assert!(matches!(error, HassiumError::Syntax { .. }));
+        assert!(error.message().starts_with("Invalid format spec \"8x\""));
+    }
+
+    #[test]
+    fn brackets_starting_a_line_start_a_statement() {
+        let tokens = tokenize("t = f\n(a, b) = t\n[1, 2]\nf(1,\n2)[0]".to_string(), "test");
+        let children = match parse(&tokens.unwrap()).ok().unwrap().kind {
+            AstKind::Block { children } => children,
+            _ => panic!("expected a block"),
+        };
+        let kinds: Vec<AstKind> = children
+            .into_iter()
+            .map(|child| match child.kind {
+                AstKind::ExpressionStatement { expression } => expression.kind,
+                kind => kind,
+            })
+            .collect();
+        assert_eq!(kinds.len(), 4);
+        assert!(matches!(&kinds[0], AstKind::Assign { right, .. }
+            if matches!(right.kind, AstKind::Id { .. })));
+        assert!(matches!(&kinds[1], AstKind::Assign { left, .. }
+            if matches!(left.kind, AstKind::Tuple { .. })));
+        assert!(matches!(kinds[2], AstKind::List { .. }));
+        assert!(matches!(&kinds[3], AstKind::Subscript { target, .. }
+            if matches!(target.kind, AstKind::Invoke { .. })));
+    }
+
+    #[test]
+    fn running_out_of_tokens_is_a_syntax_error() {
+        let error = parse_error("x = 1 +");
+        assert_eq!(error.to_string(), "test:1:8: SyntaxError: Unexpected EOF!");
+    }
+
+    #[test]
+    fn chained_calls_continue_onto_new_lines() {
+        let tokens = tokenize(
+            "n = items\n    .slice(1)\n    .length();".to_string(),
+            "test",
+        );
+        let children = match parse(&tokens.unwrap()).ok().unwrap().kind {
+            AstKind::Block { children } => children,
+            _ => panic!("expected a block"),
+        };
+        assert_eq!(children.len(), 1);
+        let right = match &children[0].kind {
+            AstKind::ExpressionStatement { expression } => match &expression.kind {
+                AstKind::Assign { right, .. } => right,
+                _ => panic!("expected an assignment"),
+            },
+            _ => panic!("expected an expression statement"),
+        };
+        assert!(matches!(&right.kind, AstKind::Invoke { target, .. }
+            if matches!(target.kind, AstKind::AttribAccess { .. })));
+    }
 }
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use crate::error::HassiumError;
+use crate::runtime::vm::VMContext;
+
+use super::defaults::{
+    new_hassium_dict, new_hassium_list, new_hassium_number, new_hassium_string, new_hassium_tuple,
+};
+use super::dict::{hash_str, DictEntries, DictEntry};
+use super::{Arity, HassiumObjectContext, ObjectId};
+
+/// A Rust value that can be handed to Hassium code
+pub trait IntoHassium {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId;
+}
+
+/// A Rust value that can be read out of a Hassium object. Objects of the wrong
+/// type are rejected with a type error rather than a panic
+pub trait FromHassium: Sized {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError>;
+}
+
+fn expected(vm: &VMContext, id: ObjectId, expected: &str) -> HassiumError {
+    let found = match vm.deref(id) {
+        Ok(obj) => obj.context.type_name(),
+        Err(error) => return error,
+    };
+    HassiumError::type_error(format!("expected {}, got {}!", expected, found))
+}
+
+impl IntoHassium for ObjectId {
+    fn into_hassium(self, _vm: &mut VMContext) -> ObjectId {
+        self
+    }
+}
+
+impl FromHassium for ObjectId {
+    fn from_hassium(_vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        Ok(id)
+    }
+}
+
+impl IntoHassium for f64 {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        new_hassium_number(vm, self)
+    }
+}
+
+impl FromHassium for f64 {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        match vm.deref(id)?.context {
+            HassiumObjectContext::Number(value) => Ok(value),
+            _ => Err(expected(vm, id, "number")),
+        }
+    }
+}
+
+/// Hassium only has f64 numbers, integers are converted through them and must
+/// be whole and in range on the way back
+macro_rules! impl_integer {
+    ($($int:ty),*) => {$(
+        impl IntoHassium for $int {
+            fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+                new_hassium_number(vm, self as f64)
+            }
+        }
+
+        impl FromHassium for $int {
+            fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+                let value = f64::from_hassium(vm, id)?;
+                if value.fract() != 0.0 || value < <$int>::MIN as f64 || value > <$int>::MAX as f64 {
+                    return Err(HassiumError::type_error(format!(
+                        "expected {}, got {}!",
+                        stringify!($int),
+                        value
+                    )));
+                }
+                Ok(value as $int)
+            }
+        }
+    )*};
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoHassium for bool {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        vm.bool(self)
+    }
+}
+
+impl FromHassium for bool {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        match vm.deref(id)?.context {
+            HassiumObjectContext::Bool(value) => Ok(value),
+            _ => Err(expected(vm, id, "bool")),
+        }
+    }
+}
+
+impl IntoHassium for String {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        new_hassium_string(vm, self)
+    }
+}
+
+impl IntoHassium for &str {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        new_hassium_string(vm, self.to_string())
+    }
+}
+
+impl FromHassium for String {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        match &vm.deref(id)?.context {
+            HassiumObjectContext::String(value) => Ok(value.clone()),
+            _ => Err(expected(vm, id, "string")),
+        }
+    }
+}
+
+impl<T: IntoHassium> IntoHassium for Vec<T> {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        let items: Vec<ObjectId> = self.into_iter().map(|item| item.into_hassium(vm)).collect();
+        new_hassium_list(vm, items)
+    }
+}
+
+impl<T: FromHassium> FromHassium for Vec<T> {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        let items = match &vm.deref(id)?.context {
+            HassiumObjectContext::List(items) => items.clone(),
+            _ => return Err(expected(vm, id, "list")),
+        };
+        items
+            .into_iter()
+            .map(|item| T::from_hassium(vm, item))
+            .collect()
+    }
+}
+
+/// Maps become a dict with string keys
+impl<T: IntoHassium> IntoHassium for HashMap<String, T> {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        let mut entries = DictEntries::default();
+        for (key, value) in self {
+            let hash = hash_str(&key);
+            let key = key.into_hassium(vm);
+            let value = value.into_hassium(vm);
+            entries.push(DictEntry { hash, key, value });
+        }
+        new_hassium_dict(vm, entries)
+    }
+}
+
+impl<T: FromHassium> FromHassium for HashMap<String, T> {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        let entries = match &vm.deref(id)?.context {
+            HassiumObjectContext::Dict(entries) => entries.clone(),
+            _ => return Err(expected(vm, id, "dict")),
+        };
+        entries
+            .iter()
+            .map(|entry| {
+                let key = String::from_hassium(vm, entry.key).map_err(|error| {
+                    HassiumError::type_error(format!("dict key {}", error.message()))
+                })?;
+                Ok((key, T::from_hassium(vm, entry.value)?))
+            })
+            .collect()
+    }
+}
+
+/// Rust tuples map to Hassium tuples of the same length
+macro_rules! impl_tuple {
+    ($len:expr; $($item:ident),*) => {
+        impl<$($item: IntoHassium),*> IntoHassium for ($($item,)*) {
+            #[allow(non_snake_case)]
+            fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+                let ($($item,)*) = self;
+                let items = vec![$($item.into_hassium(vm)),*];
+                new_hassium_tuple(vm, items)
+            }
+        }
+
+        impl<$($item: FromHassium),*> FromHassium for ($($item,)*) {
+            fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+                let items = match &vm.deref(id)?.context {
+                    HassiumObjectContext::Tuple(items) if items.len() == $len => items.clone(),
+                    _ => return Err(expected(vm, id, concat!("tuple of ", $len))),
+                };
+                let mut items = items.into_iter();
+                Ok(($($item::from_hassium(vm, items.next().unwrap())?,)*))
+            }
+        }
+    };
+}
+
+impl_tuple!(2; A, B);
+impl_tuple!(3; A, B, C);
+impl_tuple!(4; A, B, C, D);
+
+/// None maps to null
+impl<T: IntoHassium> IntoHassium for Option<T> {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        match self {
+            Some(value) => value.into_hassium(vm),
+            None => vm.null(),
+        }
+    }
+}
+
+impl<T: FromHassium> FromHassium for Option<T> {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        match vm.deref(id)?.context {
+            HassiumObjectContext::None => Ok(None),
+            _ => Ok(Some(T::from_hassium(vm, id)?)),
+        }
+    }
+}
+
+impl IntoHassium for () {
+    fn into_hassium(self, vm: &mut VMContext) -> ObjectId {
+        vm.null()
+    }
+}
+
+impl FromHassium for () {
+    fn from_hassium(vm: &mut VMContext, id: ObjectId) -> Result<Self, HassiumError> {
+        match vm.deref(id)?.context {
+            HassiumObjectContext::None => Ok(()),
+            _ => Err(expected(vm, id, "null")),
+        }
+    }
+}
+
+/// A plain Rust function whose parameters and result convert to and from
+/// Hassium objects, see VMContext::define_typed_function
+pub trait TypedFunction<Args> {
+    fn arity(&self) -> Arity;
+
+    fn call_typed(
+        &self,
+        vm: &mut VMContext,
+        name: &str,
+        args: Vec<ObjectId>,
+    ) -> Result<ObjectId, HassiumError>;
+}
+
+macro_rules! impl_typed_function {
+    ($($arg:ident),*) => {
+        impl<Func, Ret, Error, $($arg),*> TypedFunction<($($arg,)*)> for Func
+        where
+            Func: Fn($($arg),*) -> Result<Ret, Error>,
+            Ret: IntoHassium,
+            Error: Into<HassiumError>,
+            $($arg: FromHassium,)*
+        {
+            fn arity(&self) -> Arity {
+                Arity::Exact(<[&str]>::len(&[$(stringify!($arg)),*]))
+            }
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn call_typed(
+                &self,
+                vm: &mut VMContext,
+                name: &str,
+                args: Vec<ObjectId>,
+            ) -> Result<ObjectId, HassiumError> {
+                let mut args = args.into_iter().enumerate();
+                $(
+                    let (index, arg) = args.next().unwrap();
+                    let $arg = $arg::from_hassium(vm, arg).map_err(|error| {
+                        let message = format!("{}() argument {}: {}", name, index + 1, error.message());
+                        error.with_message(message)
+                    })?;
+                )*
+                match self($($arg),*) {
+                    Ok(ret) => Ok(ret.into_hassium(vm)),
+                    Err(error) => Err(error.into()),
+                }
+            }
+        }
+    };
+}
+
+impl_typed_function!();
+impl_typed_function!(A);
+impl_typed_function!(A, B);
+impl_typed_function!(A, B, C);
+impl_typed_function!(A, B, C, D);
+impl_typed_function!(A, B, C, D, E);
+impl_typed_function!(A, B, C, D, E, F);
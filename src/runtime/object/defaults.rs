@@ -1,85 +1,578 @@
-use std::collections::HashMap;
+use std::rc::Rc;
 
+use crate::error::HassiumError;
 use crate::runtime::object::ObjectId;
-use crate::runtime::vm::VMContext;
+use crate::runtime::vm::{CodeObj, VMContext};
 
-use super::{HassiumObject, HassiumObjectContext};
+use super::convert::{FromHassium, IntoHassium};
+use super::dict::{dict_method, DictEntries};
+use super::list::{list_method, tuple_method};
+use super::{Arity, HassiumObject, HassiumObjectContext, NativeFunction};
 
-pub fn get_defaults(vm: &mut VMContext) -> HashMap<String, ObjectId> {
-    let mut ret: HashMap<String, ObjectId> = HashMap::new();
-    ret.insert("println".to_string(), new_hassium_function(vm, println).id);
-    return ret;
+pub fn define_defaults(vm: &mut VMContext) {
+    // The emitter loads these for the keywords, scripts cannot rebind them
+    let true_obj = new_hassium_bool(vm, true);
+    vm.define_global("true", true_obj);
+    let false_obj = new_hassium_bool(vm, false);
+    vm.define_global("false", false_obj);
+    let null = new_hassium_none(vm);
+    vm.define_global("null", null);
+    vm.define_function("gc", Arity::Exact(0), gc);
+    vm.define_function("heapStats", Arity::Exact(0), heap_stats);
+    let heap_stats_class = new_hassium_class(vm, "HeapStats".to_string(), None);
+    vm.define_global("HeapStats", heap_stats_class);
+    vm.define_function("println", Arity::AtLeast(0), println);
+    vm.define_function("range", Arity::Between(1, 3), range);
+    let exception = new_exception_class(vm);
+    vm.define_global("Exception", exception);
+    for name in ["NameError", "TypeError"] {
+        let class = new_hassium_class(vm, name.to_string(), Some(exception));
+        vm.define_global(name, class);
+    }
+}
+
+/// Sets an attribute of an object that was just allocated
+fn init_attrib(vm: &mut VMContext, obj_id: ObjectId, name: &str, value: ObjectId) {
+    if let Some(obj) = vm.heap.get_mut(obj_id) {
+        obj.attributes.insert(name.to_string(), value);
+    }
+}
+
+fn new_exception_class(vm: &mut VMContext) -> ObjectId {
+    let class = new_hassium_class(vm, "Exception".to_string(), None);
+    let new = new_hassium_function(vm, "new", Arity::Between(0, 1), exception_new);
+    init_attrib(vm, class, "new", new);
+    let to_string = new_hassium_function(vm, "toString", Arity::Exact(0), exception_to_string);
+    init_attrib(vm, class, "toString", to_string);
+    class
+}
+
+/// Creates an instance of one of the built-in exception classes, used to turn a
+/// HassiumError into something Hassium code can catch
+pub fn new_hassium_exception(vm: &mut VMContext, class: &str, message: String) -> ObjectId {
+    let class = vm.builtin(class);
+    let obj = new_hassium_instance(vm, class);
+    let message = new_hassium_string(vm, message);
+    init_attrib(vm, obj, "message", message);
+    obj
 }
 
 pub fn new_hassium_function(
     vm: &mut VMContext,
-    func: fn(&mut VMContext, ObjectId, Vec<ObjectId>) -> ObjectId,
-) -> HassiumObject {
-    HassiumObject::new(vm, HassiumObjectContext::Function(func), None)
+    name: &str,
+    arity: Arity,
+    func: impl Fn(&mut VMContext, ObjectId, Vec<ObjectId>) -> Result<ObjectId, HassiumError> + 'static,
+) -> ObjectId {
+    let func = NativeFunction {
+        name: name.to_string(),
+        arity,
+        func: Rc::new(func),
+    };
+    HassiumObject::alloc(vm, HassiumObjectContext::Function(func), None)
 }
 
-pub fn new_hassium_number(vm: &mut VMContext, value: f64) -> HassiumObject {
-    let mut obj = HassiumObject::new(vm, HassiumObjectContext::Number(value), None);
-    bind_common_attributes(vm, &mut obj);
-    return obj;
+pub fn new_hassium_bound_function(
+    vm: &mut VMContext,
+    name: &str,
+    arity: Arity,
+    func: impl Fn(&mut VMContext, ObjectId, Vec<ObjectId>) -> Result<ObjectId, HassiumError> + 'static,
+    self_ref: ObjectId,
+) -> ObjectId {
+    let func = NativeFunction {
+        name: name.to_string(),
+        arity,
+        func: Rc::new(func),
+    };
+    HassiumObject::alloc(vm, HassiumObjectContext::Function(func), Some(self_ref))
 }
 
-pub fn new_hassium_string(vm: &mut VMContext, str: String) -> HassiumObject {
-    let mut obj = HassiumObject::new(vm, HassiumObjectContext::String(str), None);
-    bind_common_attributes(vm, &mut obj);
-    return obj;
+#[allow(clippy::too_many_arguments)]
+pub fn new_hassium_user_function(
+    vm: &mut VMContext,
+    name: String,
+    code_obj: Rc<CodeObj>,
+    param_names: Vec<String>,
+    variadic: bool,
+    class: Option<ObjectId>,
+    upvalues: Vec<ObjectId>,
+    module: ObjectId,
+    self_ref: Option<ObjectId>,
+) -> ObjectId {
+    HassiumObject::alloc(
+        vm,
+        HassiumObjectContext::UserFunction {
+            name,
+            code_obj,
+            param_names,
+            variadic,
+            class,
+            upvalues,
+            module,
+        },
+        self_ref,
+    )
 }
 
-fn bind_common_attributes(vm: &mut VMContext, obj: &mut HassiumObject) {
-    obj.attributes.insert(
-        "toNumber".to_string(),
-        new_hassium_function(vm, to_number).id,
-    );
-    obj.attributes.insert(
-        "toString".to_string(),
-        new_hassium_function(vm, to_string).id,
+pub fn new_hassium_bound_method(
+    vm: &mut VMContext,
+    func: &HassiumObject,
+    self_ref: ObjectId,
+) -> ObjectId {
+    HassiumObject::alloc(vm, func.context.clone(), Some(self_ref))
+}
+
+pub fn new_hassium_bool(vm: &mut VMContext, value: bool) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Bool(value), None)
+}
+
+/// Cells never reach Hassium code, the VM reads through them when loading a
+/// captured variable
+pub fn new_hassium_cell(vm: &mut VMContext, value: Option<ObjectId>) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Cell(value), None)
+}
+
+pub fn new_hassium_class(vm: &mut VMContext, name: String, extends: Option<ObjectId>) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Class { name, extends }, None)
+}
+
+pub fn new_hassium_instance(vm: &mut VMContext, class: ObjectId) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Instance { class }, None)
+}
+
+pub fn new_hassium_dict(vm: &mut VMContext, entries: DictEntries) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Dict(entries), None)
+}
+
+pub fn new_hassium_iterator(vm: &mut VMContext, target: ObjectId) -> ObjectId {
+    HassiumObject::alloc(
+        vm,
+        HassiumObjectContext::Iterator { target, index: 0 },
+        None,
+    )
+}
+
+pub fn new_hassium_list(vm: &mut VMContext, items: Vec<ObjectId>) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::List(items), None)
+}
+
+pub fn new_hassium_module(vm: &mut VMContext, name: String) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Module { name }, None)
+}
+
+pub fn new_hassium_none(vm: &mut VMContext) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::None, None)
+}
+
+pub fn new_hassium_number(vm: &mut VMContext, value: f64) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Number(value), None)
+}
+
+pub fn new_hassium_range(vm: &mut VMContext, start: f64, end: f64, step: f64) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Range { start, end, step }, None)
+}
+
+pub fn new_hassium_string(vm: &mut VMContext, str: String) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::String(str), None)
+}
+
+pub fn new_hassium_tuple(vm: &mut VMContext, items: Vec<ObjectId>) -> ObjectId {
+    HassiumObject::alloc(vm, HassiumObjectContext::Tuple(items), None)
+}
+
+pub(super) type NativeMethod =
+    fn(&mut VMContext, ObjectId, Vec<ObjectId>) -> Result<ObjectId, HassiumError>;
+
+/// The built-in methods every object has, such as toString, and those of its
+/// type. They are bound when looked up rather than stored on every object, so
+/// they cost nothing until used and never shadow class members
+pub fn get_common_attribute(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    attrib: &str,
+) -> Option<ObjectId> {
+    let context = &vm.deref(obj_id).ok()?.context;
+    let iterable = matches!(
+        context,
+        HassiumObjectContext::Dict(_)
+            | HassiumObjectContext::List(_)
+            | HassiumObjectContext::Range { .. }
+            | HassiumObjectContext::String(_)
+            | HassiumObjectContext::Tuple(_)
     );
+    let iterator = matches!(context, HassiumObjectContext::Iterator { .. });
+    let dict = matches!(context, HassiumObjectContext::Dict(_));
+    let list = matches!(context, HassiumObjectContext::List(_));
+    let tuple = matches!(context, HassiumObjectContext::Tuple(_));
+    match attrib {
+        "hasNext" if iterator => Some((iterator_has_next as NativeMethod, Arity::Exact(0))),
+        "iter" if iterable || iterator => Some((iter as NativeMethod, Arity::Exact(0))),
+        "next" if iterator => Some((iterator_next as NativeMethod, Arity::Exact(0))),
+        "toBool" => Some((to_bool as NativeMethod, Arity::Exact(0))),
+        "toNumber" => Some((to_number as NativeMethod, Arity::Exact(0))),
+        "toString" => Some((to_string as NativeMethod, Arity::Exact(0))),
+        _ if dict => dict_method(attrib),
+        _ if list => list_method(attrib),
+        _ if tuple => tuple_method(attrib),
+        _ => None,
+    }
+    .map(|(func, arity)| new_hassium_bound_function(vm, attrib, arity, func, obj_id))
+}
+
+/// Calls the object's toString attribute and unwraps the resulting string
+pub fn stringify(vm: &mut VMContext, obj_id: ObjectId) -> Result<String, HassiumError> {
+    let to_string_id = match vm.get_attrib(obj_id, "toString")? {
+        Some(to_string_id) => to_string_id,
+        None => {
+            return Err(HassiumError::name_error(
+                "Object has no toString!".to_string(),
+            ))
+        }
+    };
+    let hassium_str = to_string_id.invoke(vm, Vec::new())?;
+    String::from_hassium(vm, hassium_str).map_err(|error| {
+        let message = format!("toString {}", error.message());
+        error.with_message(message)
+    })
 }
 
 // Global default methods
-fn println(vm: &mut VMContext, _obj_id: ObjectId, args: Vec<ObjectId>) -> ObjectId {
-    println!("Rust is not broken");
+fn gc(
+    vm: &mut VMContext,
+    _obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let freed = vm.collect_garbage();
+    Ok(freed.into_hassium(vm))
+}
+
+fn heap_stats(
+    vm: &mut VMContext,
+    _obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let stats = vm.heap_stats();
+    let class = vm.builtin("HeapStats");
+    let obj = new_hassium_instance(vm, class);
+    for (name, value) in [
+        ("liveObjects", stats.live_objects),
+        ("totalAllocated", stats.total_allocated),
+        ("totalFreed", stats.total_freed),
+        ("collections", stats.collections),
+        ("threshold", stats.threshold),
+    ] {
+        let value = value.into_hassium(vm);
+        init_attrib(vm, obj, name, value);
+    }
+    Ok(obj)
+}
+
+fn println(
+    vm: &mut VMContext,
+    _obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
     for arg_id in args {
-        let arg = vm.deref(arg_id);
-        let hassium_str = match vm.deref(arg.getattr("to_string")).context {
-            HassiumObjectContext::Function(func) => func(vm, arg.id, Vec::new()),
-            _ => panic!(),
-        };
-
-        match &vm.deref(hassium_str).context {
-            HassiumObjectContext::String(value) => println!("{}", value),
-            _ => panic!(),
+        println!("{}", stringify(vm, arg_id)?);
+    }
+
+    Ok(vm.null())
+}
+
+fn range(
+    vm: &mut VMContext,
+    _obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let mut bounds: Vec<f64> = Vec::new();
+    for arg in &args {
+        let bound = f64::from_hassium(vm, *arg)
+            .map_err(|error| HassiumError::type_error(format!("range() {}", error.message())))?;
+        bounds.push(bound);
+    }
+    let (start, end, step) = match bounds[..] {
+        [end] => (0.0, end, 1.0),
+        [start, end] => (start, end, 1.0),
+        [start, end, step] => (start, end, step),
+        _ => {
+            return Err(HassiumError::type_error(format!(
+                "range() expected 1 to 3 arguments, got {}!",
+                args.len()
+            )))
+        }
+    };
+    if step == 0.0 {
+        return Err(HassiumError::runtime_error(
+            "range() step cannot be zero!".to_string(),
+        ));
+    }
+    Ok(new_hassium_range(vm, start, end, step))
+}
+
+// Iteration methods
+fn iter(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    match vm.deref(obj_id)?.context {
+        HassiumObjectContext::Iterator { .. } => Ok(obj_id),
+        _ => Ok(new_hassium_iterator(vm, obj_id)),
+    }
+}
+
+/// Returns the item an iterator is positioned at without advancing it
+fn iterator_current(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+) -> Result<Option<ObjectId>, HassiumError> {
+    let (target, index) = match vm.deref(obj_id)?.context {
+        HassiumObjectContext::Iterator { target, index } => (target, index),
+        _ => {
+            return Err(HassiumError::type_error(
+                "Object is not an iterator!".to_string(),
+            ))
+        }
+    };
+    match &vm.deref(target)?.context {
+        // Dicts iterate over their keys
+        HassiumObjectContext::Dict(entries) => Ok(entries.get(index).map(|entry| entry.key)),
+        HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items) => {
+            Ok(items.get(index).copied())
+        }
+        HassiumObjectContext::Range { start, end, step } => {
+            let value = start + index as f64 * step;
+            if (*step > 0.0 && value < *end) || (*step < 0.0 && value > *end) {
+                Ok(Some(new_hassium_number(vm, value)))
+            } else {
+                Ok(None)
+            }
+        }
+        HassiumObjectContext::String(string) => match string.chars().nth(index) {
+            Some(char) => Ok(Some(new_hassium_string(vm, char.to_string()))),
+            None => Ok(None),
+        },
+        _ => Err(HassiumError::type_error(
+            "Object is not iterable!".to_string(),
+        )),
+    }
+}
+
+fn iterator_has_next(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let has_next = iterator_current(vm, obj_id)?.is_some();
+    Ok(vm.bool(has_next))
+}
+
+fn iterator_next(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let value = match iterator_current(vm, obj_id)? {
+        Some(value) => value,
+        None => {
+            return Err(HassiumError::runtime_error(
+                "Iterator is exhausted!".to_string(),
+            ))
         }
+    };
+    let obj = vm.heap.get_mut(obj_id).unwrap();
+    if let HassiumObjectContext::Iterator { index, .. } = &mut obj.context {
+        *index += 1;
     }
+    Ok(value)
+}
 
-    ObjectId(0)
+// Exception methods
+fn exception_new(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let message = match args.first() {
+        Some(message) => *message,
+        None => new_hassium_string(vm, String::new()),
+    };
+    vm.set_attrib(obj_id, "message", message)?;
+    Ok(obj_id)
+}
+
+fn exception_to_string(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let class_name = match &vm.deref(obj_id)?.context {
+        HassiumObjectContext::Instance { class } => match &vm.deref(*class)?.context {
+            HassiumObjectContext::Class { name, .. } => name.clone(),
+            _ => "Exception".to_string(),
+        },
+        _ => "Exception".to_string(),
+    };
+    let message = match vm.get_attrib(obj_id, "message")? {
+        Some(message) => stringify(vm, message)?,
+        None => String::new(),
+    };
+    Ok(new_hassium_string(
+        vm,
+        format!("{}: {}", class_name, message),
+    ))
 }
 
 // Common instance methods on types
-fn to_number(vm: &mut VMContext, obj_id: ObjectId, _args: Vec<ObjectId>) -> ObjectId {
-    println!("to_number");
-    let obj = vm.deref(obj_id);
+fn to_bool(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let value = vm.is_truthy(obj_id)?;
+    Ok(vm.bool(value))
+}
+
+fn to_number(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let obj = Rc::clone(vm.deref(obj_id)?);
     match &obj.context {
-        HassiumObjectContext::Function(_) => panic!(),
-        HassiumObjectContext::None => panic!(),
-        HassiumObjectContext::Number(value) => new_hassium_number(vm, *value).id,
-        HassiumObjectContext::String(string) => new_hassium_number(vm, string.parse().unwrap()).id,
-        HassiumObjectContext::Type(_) => panic!(),
+        HassiumObjectContext::Number(value) => Ok(new_hassium_number(vm, *value)),
+        HassiumObjectContext::String(string) => match string.parse() {
+            Ok(value) => Ok(new_hassium_number(vm, value)),
+            Err(_) => Err(HassiumError::type_error(format!(
+                "Could not convert \"{}\" to a number!",
+                string
+            ))),
+        },
+        _ => Err(HassiumError::type_error(
+            "Object cannot be converted to a number!".to_string(),
+        )),
     }
 }
 
-fn to_string(vm: &mut VMContext, obj_id: ObjectId, _args: Vec<ObjectId>) -> ObjectId {
-    let obj = vm.deref(obj_id);
-    match &obj.context {
-        HassiumObjectContext::Function(_) => new_hassium_string(vm, "function()".to_string()).id,
-        HassiumObjectContext::None => new_hassium_string(vm, "None".to_string()).id,
-        HassiumObjectContext::Number(value) => new_hassium_string(vm, value.to_string()).id,
-        HassiumObjectContext::String(string) => new_hassium_string(vm, string.clone()).id,
-        HassiumObjectContext::Type(name) => new_hassium_string(vm, name.clone()).id,
+fn to_string(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let obj = vm.deref(obj_id)?;
+    Ok(match &obj.context {
+        HassiumObjectContext::Bool(value) => new_hassium_string(vm, value.to_string()),
+        HassiumObjectContext::Cell(_) => new_hassium_string(vm, "cell".to_string()),
+        HassiumObjectContext::Class { name, .. } => new_hassium_string(vm, name.clone()),
+        HassiumObjectContext::Dict(entries) => {
+            let entries = entries.clone();
+            let string = vm.print_container(obj_id, |vm| {
+                let mut strings = Vec::new();
+                for entry in entries.iter() {
+                    let key = stringify(vm, entry.key)?;
+                    strings.push(format!("{}: {}", key, stringify(vm, entry.value)?));
+                }
+                Ok(format!("{{{}}}", strings.join(", ")))
+            })?;
+            new_hassium_string(vm, string.unwrap_or_else(|| "{...}".to_string()))
+        }
+        HassiumObjectContext::Function(func) => new_hassium_string(vm, format!("{}()", func.name)),
+        HassiumObjectContext::Instance { class } => {
+            let class_name = match &vm.deref(*class)?.context {
+                HassiumObjectContext::Class { name, .. } => name.clone(),
+                _ => return Err(HassiumError::type_error("Invalid instance!".to_string())),
+            };
+            new_hassium_string(vm, format!("{} instance", class_name))
+        }
+        HassiumObjectContext::Iterator { .. } => new_hassium_string(vm, "iterator".to_string()),
+        HassiumObjectContext::List(items) => {
+            let items = items.clone();
+            let string = vm.print_container(obj_id, |vm| {
+                let mut strings = Vec::new();
+                for item in items {
+                    strings.push(stringify(vm, item)?);
+                }
+                Ok(format!("[{}]", strings.join(", ")))
+            })?;
+            new_hassium_string(vm, string.unwrap_or_else(|| "[...]".to_string()))
+        }
+        HassiumObjectContext::Module { name } => new_hassium_string(vm, format!("module {}", name)),
+        HassiumObjectContext::None => new_hassium_string(vm, "null".to_string()),
+        HassiumObjectContext::Number(value) => new_hassium_string(vm, value.to_string()),
+        HassiumObjectContext::Range { start, end, step } => {
+            new_hassium_string(vm, format!("range({}, {}, {})", start, end, step))
+        }
+        HassiumObjectContext::String(string) => new_hassium_string(vm, string.clone()),
+        HassiumObjectContext::Tuple(items) => {
+            let items = items.clone();
+            let string = vm.print_container(obj_id, |vm| {
+                let mut strings = Vec::new();
+                for item in &items {
+                    strings.push(stringify(vm, *item)?);
+                }
+                // A trailing comma tells a one item tuple from a parenthesized value
+                Ok(match strings.len() {
+                    1 => format!("({},)", strings[0]),
+                    _ => format!("({})", strings.join(", ")),
+                })
+            })?;
+            new_hassium_string(vm, string.unwrap_or_else(|| "(...)".to_string()))
+        }
+        HassiumObjectContext::UserFunction { name, .. } => {
+            new_hassium_string(vm, format!("{}()", name))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_allocate_a_single_object() {
+        let mut vm = VMContext::new();
+        let before = vm.heap_stats().total_allocated;
+        new_hassium_number(&mut vm, 1.0);
+        new_hassium_string(&mut vm, "a".to_string());
+        assert_eq!(vm.heap_stats().total_allocated - before, 2);
+    }
+
+    #[test]
+    fn attributes_set_after_allocation_are_kept() {
+        let mut vm = VMContext::new();
+        let exception = new_hassium_exception(&mut vm, "TypeError", "Bad!".to_string());
+        let message = vm.get_attrib(exception, "message").unwrap().unwrap();
+        assert_eq!(String::from_hassium(&mut vm, message).unwrap(), "Bad!");
+        vm.run_source(
+            "stats = heapStats();
+live = stats.liveObjects;",
+            "test",
+        )
+        .unwrap();
+        let live = vm.get_global("live").unwrap();
+        assert!(f64::from_hassium(&mut vm, live).unwrap() > 0.0);
+    }
+
+    #[test]
+    fn containers_that_hold_themselves_print_once() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "a = [1];\na.add(a);\nd = {\"a\": a};\nd[\"d\"] = d;\ns = (d, 2).toString();",
+            "test",
+        )
+        .unwrap();
+        let string = vm.get_global("s").unwrap();
+        assert_eq!(
+            String::from_hassium(&mut vm, string).unwrap(),
+            "({a: [1, [...]], d: {...}}, 2)"
+        );
+    }
+
+    #[test]
+    fn common_attributes_are_bound_on_lookup() {
+        let mut vm = VMContext::new();
+        let number = new_hassium_number(&mut vm, 4.0);
+        let to_string = vm.get_attrib(number, "toString").unwrap().unwrap();
+        let string = to_string.invoke(&mut vm, Vec::new()).unwrap();
+        assert_eq!(String::from_hassium(&mut vm, string).unwrap(), "4");
     }
 }
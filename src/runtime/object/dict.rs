@@ -0,0 +1,406 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::error::HassiumError;
+use crate::runtime::vm::VMContext;
+
+use super::convert::{FromHassium, IntoHassium};
+use super::defaults::{new_hassium_list, NativeMethod};
+use super::{Arity, HassiumObjectContext, ObjectId};
+
+#[derive(Clone, Copy)]
+pub struct DictEntry {
+    pub hash: u64,
+    pub key: ObjectId,
+    pub value: ObjectId,
+}
+
+/// The entries of a dict in insertion order. Entries are indexed by the hash of
+/// their key, keys with the same hash are told apart with `keys_equal`
+#[derive(Clone, Default)]
+pub struct DictEntries {
+    entries: Vec<DictEntry>,
+    buckets: HashMap<u64, Vec<usize>>,
+}
+
+impl DictEntries {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&DictEntry> {
+        self.entries.get(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DictEntry> {
+        self.entries.iter()
+    }
+
+    /// The positions and keys of the entries whose key hashes to `hash`
+    fn candidates(&self, hash: u64) -> Vec<(usize, ObjectId)> {
+        match self.buckets.get(&hash) {
+            Some(indices) => indices
+                .iter()
+                .map(|index| (*index, self.entries[*index].key))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Appends an entry, the caller must know that its key is not in the dict yet
+    pub fn push(&mut self, entry: DictEntry) {
+        self.buckets
+            .entry(entry.hash)
+            .or_default()
+            .push(self.entries.len());
+        self.entries.push(entry);
+    }
+
+    /// Removes the entry at `index`. Only the bucket of that entry and those of
+    /// the entries after it, which each move down one place, need updating
+    fn remove(&mut self, index: usize) -> DictEntry {
+        let entry = self.entries.remove(index);
+        if let Some(indices) = self.buckets.get_mut(&entry.hash) {
+            indices.retain(|other| *other != index);
+            if indices.is_empty() {
+                self.buckets.remove(&entry.hash);
+            }
+        }
+        for (position, moved) in self.entries.iter().enumerate().skip(index) {
+            if let Some(indices) = self.buckets.get_mut(&moved.hash) {
+                for other in indices.iter_mut().filter(|other| **other == position + 1) {
+                    *other = position;
+                }
+            }
+        }
+        entry
+    }
+}
+
+/// The methods every dict has, looked up lazily by get_common_attribute
+pub(super) fn dict_method(attrib: &str) -> Option<(NativeMethod, Arity)> {
+    match attrib {
+        "containsKey" => Some((dict_contains_key as NativeMethod, Arity::Exact(1))),
+        "items" => Some((dict_items as NativeMethod, Arity::Exact(0))),
+        "keys" => Some((dict_keys as NativeMethod, Arity::Exact(0))),
+        "length" => Some((dict_length as NativeMethod, Arity::Exact(0))),
+        "remove" => Some((dict_remove as NativeMethod, Arity::Exact(1))),
+        "values" => Some((dict_values as NativeMethod, Arity::Exact(0))),
+        _ => None,
+    }
+}
+
+pub fn hash_str(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    "string".hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes an object used as a dict key. Values hash by value, tuples by their
+/// items, instances of a class defining `hash` by the number it returns and
+/// other objects by identity. Lists and dicts can change after being added, so
+/// they cannot be keys
+pub fn hash_key(vm: &mut VMContext, key: ObjectId) -> Result<u64, HassiumError> {
+    let context = &vm.deref(key)?.context;
+    let mut hasher = DefaultHasher::new();
+    context.type_name().hash(&mut hasher);
+    match context {
+        HassiumObjectContext::Bool(value) => value.hash(&mut hasher),
+        HassiumObjectContext::None => (),
+        // 0 and -0 are equal so they must hash the same
+        HassiumObjectContext::Number(value) => (value + 0.0).to_bits().hash(&mut hasher),
+        HassiumObjectContext::String(value) => return Ok(hash_str(value)),
+        HassiumObjectContext::Tuple(items) => {
+            for item in items.clone() {
+                hash_key(vm, item)?.hash(&mut hasher);
+            }
+        }
+        HassiumObjectContext::Instance { .. } => {
+            if vm.get_attrib(key, "hash")?.is_some() {
+                let hash = vm.call_attrib(key, "hash", Vec::new())?;
+                let hash = f64::from_hassium(vm, hash).map_err(|error| {
+                    HassiumError::type_error(format!(
+                        "hash must return a number, {}",
+                        error.message()
+                    ))
+                })?;
+                hash.to_bits().hash(&mut hasher);
+            } else {
+                key.hash(&mut hasher);
+            }
+        }
+        HassiumObjectContext::Class { .. }
+        | HassiumObjectContext::Function(_)
+        | HassiumObjectContext::Module { .. }
+        | HassiumObjectContext::UserFunction { .. } => key.hash(&mut hasher),
+        context => {
+            return Err(HassiumError::type_error(format!(
+                "Unhashable type: {}!",
+                context.type_name()
+            )))
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// Key equality, instances of a class defining `equals` decide for themselves,
+/// tuples compare their items as keys, matching how hash_key hashes them, and
+/// everything else compares like `==`
+pub fn keys_equal(
+    vm: &mut VMContext,
+    key: ObjectId,
+    other: ObjectId,
+) -> Result<bool, HassiumError> {
+    if let HassiumObjectContext::Instance { .. } = vm.deref(key)?.context {
+        if vm.get_attrib(key, "equals")?.is_some() {
+            let value = vm.call_attrib(key, "equals", vec![other])?;
+            return vm.is_truthy(value);
+        }
+    }
+    let tuples = match (&vm.deref(key)?.context, &vm.deref(other)?.context) {
+        (HassiumObjectContext::Tuple(items), HassiumObjectContext::Tuple(others)) => {
+            Some((items.clone(), others.clone()))
+        }
+        _ => None,
+    };
+    if let Some((items, others)) = tuples {
+        if items.len() != others.len() {
+            return Ok(false);
+        }
+        for (item, other) in items.into_iter().zip(others) {
+            if !keys_equal(vm, item, other)? {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+    vm.values_equal(key, other)
+}
+
+fn entries(vm: &VMContext, obj_id: ObjectId) -> Result<&DictEntries, HassiumError> {
+    match &vm.deref(obj_id)?.context {
+        HassiumObjectContext::Dict(entries) => Ok(entries),
+        _ => Err(HassiumError::type_error(
+            "Object is not a dict!".to_string(),
+        )),
+    }
+}
+
+/// Gives `edit` the entries of the dict in the heap to change in place
+fn edit_entries<T>(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    edit: impl FnOnce(&mut DictEntries) -> T,
+) -> Result<T, HassiumError> {
+    match vm.heap.get_mut(obj_id).map(|obj| &mut obj.context) {
+        Some(HassiumObjectContext::Dict(entries)) => Ok(edit(entries)),
+        _ => Err(HassiumError::type_error(
+            "Object is not a dict!".to_string(),
+        )),
+    }
+}
+
+/// Returns the hash of `key` along with the position of its entry, if any. The
+/// candidates are collected first since `equals` may run user code, which can
+/// also remove entries and move the one found, the search then starts over
+fn find(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    key: ObjectId,
+) -> Result<(u64, Option<usize>), HassiumError> {
+    let hash = hash_key(vm, key)?;
+    'search: loop {
+        let candidates = entries(vm, obj_id)?.candidates(hash);
+        for (index, other) in candidates {
+            if keys_equal(vm, other, key)? {
+                if entries(vm, obj_id)?.get(index).map(|entry| entry.key) != Some(other) {
+                    continue 'search;
+                }
+                return Ok((hash, Some(index)));
+            }
+        }
+        return Ok((hash, None));
+    }
+}
+
+pub fn dict_get(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    key: ObjectId,
+) -> Result<Option<ObjectId>, HassiumError> {
+    let index = find(vm, obj_id, key)?.1;
+    Ok(index
+        .and_then(|index| entries(vm, obj_id).ok()?.get(index))
+        .map(|entry| entry.value))
+}
+
+/// Replaces the value of an existing key in place, new keys go at the end
+pub fn dict_set(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    key: ObjectId,
+    value: ObjectId,
+) -> Result<(), HassiumError> {
+    let (hash, index) = find(vm, obj_id, key)?;
+    edit_entries(vm, obj_id, |entries| match index {
+        Some(index) => entries.entries[index].value = value,
+        None => entries.push(DictEntry { hash, key, value }),
+    })
+}
+
+fn dict_contains_key(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let found = find(vm, obj_id, args[0])?.1.is_some();
+    Ok(vm.bool(found))
+}
+
+/// Returns a list of `(key, value)` tuples
+fn dict_items(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let items = entries(vm, obj_id)?
+        .iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect::<Vec<_>>();
+    Ok(items.into_hassium(vm))
+}
+
+fn dict_keys(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let keys = entries(vm, obj_id)?.iter().map(|entry| entry.key).collect();
+    Ok(new_hassium_list(vm, keys))
+}
+
+fn dict_length(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let len = entries(vm, obj_id)?.len();
+    Ok(len.into_hassium(vm))
+}
+
+/// Removes the entry for the key, returning whether there was one
+fn dict_remove(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let index = find(vm, obj_id, args[0])?.1;
+    if let Some(index) = index {
+        edit_entries(vm, obj_id, |entries| entries.remove(index))?;
+    }
+    Ok(vm.bool(index.is_some()))
+}
+
+fn dict_values(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let values = entries(vm, obj_id)?
+        .iter()
+        .map(|entry| entry.value)
+        .collect();
+    Ok(new_hassium_list(vm, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::runtime::object::convert::FromHassium;
+    use crate::runtime::vm::VMContext;
+
+    #[test]
+    fn tuple_keys_compare_their_items_with_equals() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "class P {
+                func new(x) { self.x = x; }
+                func hash() { return self.x; }
+                func equals(other) { return self.x == other.x; }
+            }
+            d = {(P(1), 2): 10};
+            found = d[(P(1), 2)];
+            other = d.containsKey((P(1), 3));",
+            "test",
+        )
+        .unwrap();
+        let found = vm.get_global("found").unwrap();
+        let other = vm.get_global("other").unwrap();
+        assert_eq!(f64::from_hassium(&mut vm, found).unwrap(), 10.0);
+        assert!(!bool::from_hassium(&mut vm, other).unwrap());
+    }
+
+    #[test]
+    fn keys_may_change_the_dict_while_compared() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "class K {
+                func new(n) { self.n = n; }
+                func hash() { return 1; }
+                func equals(other) { d.remove(\"x\"); return self.n == other.n; }
+            }
+            d = {\"x\": 1, K(1): \"a\"};
+            d[K(1)] = \"b\";
+            removed = d.remove(K(1));
+            length = d.length();",
+            "test",
+        )
+        .unwrap();
+        let length = vm.get_global("length").unwrap();
+        assert_eq!(f64::from_hassium(&mut vm, length).unwrap(), 0.0);
+    }
+
+    /// Runs `count` rounds of setting, reading, checking and removing keys and
+    /// returns how long they took
+    fn time_dict_rounds(count: usize) -> Duration {
+        let mut vm = VMContext::new();
+        let code = format!(
+            "d = {{}};
+            for (i = 0; i < {}; i = i + 1) {{
+                d[i] = i;
+                d[-1 - i] = i;
+                d.remove(-1 - i);
+                found = d[i] == i && d.containsKey(i);
+            }}
+            length = d.length();",
+            count
+        );
+        let start = Instant::now();
+        vm.run_source(&code, "test").unwrap();
+        let elapsed = start.elapsed();
+        let length = vm.get_global("length").unwrap();
+        assert_eq!(f64::from_hassium(&mut vm, length).unwrap(), count as f64);
+        elapsed
+    }
+
+    #[test]
+    fn large_dicts_take_linear_time() {
+        // Four times the rounds would take sixteen times as long if each round
+        // walked the whole dict, allow plenty of room for a noisy machine
+        let small = time_dict_rounds(2000);
+        let large = time_dict_rounds(8000);
+        assert!(
+            large < small * 10,
+            "2000 rounds took {:?}, 8000 took {:?}",
+            small,
+            large
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use super::{HassiumObject, HassiumObjectContext, ObjectId};
+
+/// Collections run once this many objects were allocated since the last one,
+/// the threshold then grows with the number of objects that survived
+const MIN_GC_THRESHOLD: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub live_objects: usize,
+    pub total_allocated: usize,
+    pub total_freed: usize,
+    pub collections: usize,
+    pub threshold: usize,
+}
+
+/// Owns every object a VM allocates. Ids are handed out per heap, so separate
+/// VMs never share objects or clash on ids, even when run on several threads
+pub struct Heap {
+    objects: HashMap<ObjectId, Rc<HassiumObject>>,
+    next_id: usize,
+    allocated_since_gc: usize,
+    threshold: usize,
+    total_freed: usize,
+    collections: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: HashMap::new(),
+            next_id: 0,
+            allocated_since_gc: 0,
+            threshold: MIN_GC_THRESHOLD,
+            total_freed: 0,
+            collections: 0,
+        }
+    }
+
+    pub fn next_id(&mut self) -> ObjectId {
+        let id = ObjectId(self.next_id);
+        self.next_id += 1;
+        self.allocated_since_gc += 1;
+        id
+    }
+
+    pub fn get(&self, id: ObjectId) -> Option<&Rc<HassiumObject>> {
+        self.objects.get(&id)
+    }
+
+    /// Objects are shared with anything that cloned their Rc, writes go to a
+    /// fresh copy in that case
+    pub fn get_mut(&mut self, id: ObjectId) -> Option<&mut HassiumObject> {
+        self.objects.get_mut(&id).map(Rc::make_mut)
+    }
+
+    /// Stores a newly allocated object under its id
+    pub fn insert(&mut self, obj: HassiumObject) {
+        self.objects.insert(obj.id, Rc::new(obj));
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.allocated_since_gc >= self.threshold
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            live_objects: self.objects.len(),
+            total_allocated: self.next_id,
+            total_freed: self.total_freed,
+            collections: self.collections,
+            threshold: self.threshold,
+        }
+    }
+
+    /// Mark and sweep, frees every object not reachable from `roots` and
+    /// returns how many were freed
+    pub fn collect(&mut self, roots: Vec<ObjectId>) -> usize {
+        let mut marked: HashSet<ObjectId> = HashSet::new();
+        let mut pending = roots;
+        while let Some(id) = pending.pop() {
+            if !marked.insert(id) {
+                continue;
+            }
+            if let Some(obj) = self.objects.get(&id) {
+                pending.extend(references(obj));
+            }
+        }
+
+        let before = self.objects.len();
+        self.objects.retain(|id, _| marked.contains(id));
+        let freed = before - self.objects.len();
+
+        self.total_freed += freed;
+        self.collections += 1;
+        self.allocated_since_gc = 0;
+        self.threshold = MIN_GC_THRESHOLD.max(self.objects.len() * 2);
+        freed
+    }
+}
+
+/// Every object id held by `obj`, which keeps those objects alive
+fn references(obj: &HassiumObject) -> Vec<ObjectId> {
+    let mut ret: Vec<ObjectId> = obj.attributes.values().copied().collect();
+    ret.extend(obj.self_ref);
+    match &obj.context {
+        HassiumObjectContext::Cell(value) => ret.extend(value),
+        HassiumObjectContext::Class { extends, .. } => ret.extend(extends),
+        HassiumObjectContext::Dict(entries) => {
+            ret.extend(entries.iter().flat_map(|entry| [entry.key, entry.value]))
+        }
+        HassiumObjectContext::Instance { class } => ret.push(*class),
+        HassiumObjectContext::Iterator { target, .. } => ret.push(*target),
+        HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items) => ret.extend(items),
+        HassiumObjectContext::UserFunction {
+            class,
+            upvalues,
+            module,
+            ..
+        } => {
+            ret.extend(class);
+            ret.extend(upvalues);
+            ret.push(*module);
+        }
+        HassiumObjectContext::Bool(_)
+        | HassiumObjectContext::Function(_)
+        | HassiumObjectContext::Module { .. }
+        | HassiumObjectContext::None
+        | HassiumObjectContext::Number(_)
+        | HassiumObjectContext::Range { .. }
+        | HassiumObjectContext::String(_) => (),
+    }
+    ret
+}
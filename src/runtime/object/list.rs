@@ -0,0 +1,230 @@
+use std::cmp::Ordering;
+
+use crate::error::HassiumError;
+use crate::runtime::vm::VMContext;
+
+use super::convert::{FromHassium, IntoHassium};
+use super::defaults::{new_hassium_list, NativeMethod};
+use super::{Arity, HassiumObjectContext, ObjectId};
+
+/// The methods every list has, looked up lazily by get_common_attribute
+pub(super) fn list_method(attrib: &str) -> Option<(NativeMethod, Arity)> {
+    match attrib {
+        "add" => Some((list_add as NativeMethod, Arity::Exact(1))),
+        "contains" => Some((list_contains as NativeMethod, Arity::Exact(1))),
+        "indexOf" => Some((list_index_of as NativeMethod, Arity::Exact(1))),
+        "insert" => Some((list_insert as NativeMethod, Arity::Exact(2))),
+        "length" => Some((list_length as NativeMethod, Arity::Exact(0))),
+        "remove" => Some((list_remove as NativeMethod, Arity::Exact(1))),
+        "reverse" => Some((list_reverse as NativeMethod, Arity::Exact(0))),
+        "slice" => Some((list_slice as NativeMethod, Arity::Between(1, 2))),
+        "sort" => Some((list_sort as NativeMethod, Arity::Exact(0))),
+        _ => None,
+    }
+}
+
+/// Tuples cannot be changed, so they only share the methods reading a list
+pub(super) fn tuple_method(attrib: &str) -> Option<(NativeMethod, Arity)> {
+    match attrib {
+        "contains" | "indexOf" | "length" => list_method(attrib),
+        _ => None,
+    }
+}
+
+/// Reads `index` as a whole number, negative indices count back from `len`
+fn read_index(vm: &mut VMContext, index: ObjectId, len: usize) -> Result<i64, HassiumError> {
+    let index = f64::from_hassium(vm, index)
+        .map_err(|error| HassiumError::type_error(format!("Index {}", error.message())))?;
+    if index.fract() != 0.0 {
+        return Err(HassiumError::type_error(format!(
+            "Index must be a whole number, got {}!",
+            index
+        )));
+    }
+    let index = index as i64;
+    Ok(if index < 0 { index + len as i64 } else { index })
+}
+
+/// Reads `index` as the position of one of `len` items in a list, tuple or string
+pub fn sequence_index(
+    vm: &mut VMContext,
+    index: ObjectId,
+    len: usize,
+) -> Result<usize, HassiumError> {
+    let position = read_index(vm, index, len)?;
+    if position < 0 || position >= len as i64 {
+        let index = f64::from_hassium(vm, index)?;
+        return Err(HassiumError::runtime_error(format!(
+            "Index {} out of range for length {}!",
+            index, len
+        )));
+    }
+    Ok(position as usize)
+}
+
+fn items(vm: &VMContext, obj_id: ObjectId) -> Result<Vec<ObjectId>, HassiumError> {
+    match &vm.deref(obj_id)?.context {
+        HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items) => Ok(items.clone()),
+        _ => Err(HassiumError::type_error(
+            "Object is not a list!".to_string(),
+        )),
+    }
+}
+
+/// Gives `edit` the items of the list in the heap to change in place
+fn edit_items<T>(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    edit: impl FnOnce(&mut Vec<ObjectId>) -> T,
+) -> Result<T, HassiumError> {
+    match vm.heap.get_mut(obj_id).map(|obj| &mut obj.context) {
+        Some(HassiumObjectContext::List(items)) => Ok(edit(items)),
+        _ => Err(HassiumError::type_error(
+            "Object is not a list!".to_string(),
+        )),
+    }
+}
+
+/// The position of the first item equal to `value`
+fn find(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    value: ObjectId,
+) -> Result<Option<usize>, HassiumError> {
+    for (index, item) in items(vm, obj_id)?.into_iter().enumerate() {
+        if vm.values_equal(item, value)? {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
+fn list_add(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    edit_items(vm, obj_id, |items| items.push(args[0]))?;
+    Ok(vm.null())
+}
+
+fn list_contains(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let found = find(vm, obj_id, args[0])?.is_some();
+    Ok(vm.bool(found))
+}
+
+/// Returns -1 when no item is equal to the argument
+fn list_index_of(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let index = match find(vm, obj_id, args[0])? {
+        Some(index) => index as f64,
+        None => -1.0,
+    };
+    Ok(index.into_hassium(vm))
+}
+
+/// Inserts before the item at the index, an index equal to the length appends
+fn list_insert(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let len = items(vm, obj_id)?.len();
+    let index = match read_index(vm, args[0], len)? {
+        index if index == len as i64 => len,
+        _ => sequence_index(vm, args[0], len)?,
+    };
+    edit_items(vm, obj_id, |items| items.insert(index, args[1]))?;
+    Ok(vm.null())
+}
+
+fn list_length(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let len = items(vm, obj_id)?.len();
+    Ok(len.into_hassium(vm))
+}
+
+/// Removes the first item equal to the argument, returning whether one was found
+fn list_remove(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let found = find(vm, obj_id, args[0])?;
+    if let Some(index) = found {
+        edit_items(vm, obj_id, |items| items.remove(index))?;
+    }
+    Ok(vm.bool(found.is_some()))
+}
+
+fn list_reverse(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    edit_items(vm, obj_id, |items| items.reverse())?;
+    Ok(vm.null())
+}
+
+/// Returns a new list of the items from `start` up to but not including `end`,
+/// which defaults to the length. Both may be negative and are clamped to the list
+fn list_slice(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let items = items(vm, obj_id)?;
+    let mut bounds = Vec::new();
+    for arg in &args {
+        let bound = read_index(vm, *arg, items.len())?;
+        bounds.push(bound.clamp(0, items.len() as i64) as usize);
+    }
+    let start = bounds[0];
+    let end = bounds.get(1).copied().unwrap_or(items.len());
+    let slice = items[start..end.max(start)].to_vec();
+    Ok(new_hassium_list(vm, slice))
+}
+
+/// Sorts in place with `<`, so numbers, strings and instances overloading
+/// `__lt__` can be sorted
+fn list_sort(
+    vm: &mut VMContext,
+    obj_id: ObjectId,
+    _args: Vec<ObjectId>,
+) -> Result<ObjectId, HassiumError> {
+    let mut items = items(vm, obj_id)?;
+    let mut error = None;
+    items.sort_by(|a, b| {
+        if error.is_some() {
+            return Ordering::Equal;
+        }
+        let ordering = vm.less_than(*a, *b).and_then(|less| {
+            if less {
+                return Ok(Ordering::Less);
+            }
+            Ok(match vm.less_than(*b, *a)? {
+                true => Ordering::Greater,
+                false => Ordering::Equal,
+            })
+        });
+        ordering.unwrap_or_else(|err| {
+            error = Some(err);
+            Ordering::Equal
+        })
+    });
+    if let Some(error) = error {
+        return Err(error);
+    }
+    edit_items(vm, obj_id, |list| *list = items)?;
+    Ok(vm.null())
+}
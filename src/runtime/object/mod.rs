@@ -1,11 +1,16 @@
+pub mod convert;
 pub mod defaults;
+pub mod dict;
+pub mod heap;
+pub mod list;
 
 use core::fmt;
-use std::{collections::HashMap, ops::Add, rc::Rc};
+use std::{collections::HashMap, rc::Rc};
 
-use super::vm::VMContext;
+use self::dict::DictEntries;
+use super::vm::{CodeObj, VMContext};
+use crate::error::HassiumError;
 
-static mut HASSIUM_OBJECT_ID: usize = 0;
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct ObjectId(usize);
 
@@ -15,56 +20,155 @@ impl fmt::Display for ObjectId {
     }
 }
 
+/// The Rust side of a native function. It receives the object it is bound to,
+/// or the function itself when unbound, followed by the call's arguments
+pub type NativeFn =
+    dyn Fn(&mut VMContext, ObjectId, Vec<ObjectId>) -> Result<ObjectId, HassiumError>;
+
+/// How many arguments a native function accepts, checked before it is called
+#[derive(Clone, Copy, Debug)]
+pub enum Arity {
+    Exact(usize),
+    Between(usize, usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(expected) => count == expected,
+            Arity::Between(min, max) => min <= count && count <= max,
+            Arity::AtLeast(min) => count >= min,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exact(expected) => write!(f, "{}", expected),
+            Arity::Between(min, max) => write!(f, "{} to {}", min, max),
+            Arity::AtLeast(min) => write!(f, "at least {}", min),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: Arity,
+    pub func: Rc<NativeFn>,
+}
+
 #[derive(Clone)]
 pub enum HassiumObjectContext {
-    Function(fn(&mut VMContext, ObjectId, args: Vec<ObjectId>) -> ObjectId),
+    Bool(bool),
+    /// Holds a captured local so the frame and its closures share one value
+    Cell(Option<ObjectId>),
+    Class {
+        name: String,
+        extends: Option<ObjectId>,
+    },
+    Dict(DictEntries),
+    Function(NativeFunction),
+    Instance {
+        class: ObjectId,
+    },
+    /// Walks a built-in iterable, `index` is the position of the next item
+    Iterator {
+        target: ObjectId,
+        index: usize,
+    },
+    List(Vec<ObjectId>),
+    /// A namespace of attributes, such as a module defined by the host
+    Module {
+        name: String,
+    },
     None,
     Number(f64),
+    Range {
+        start: f64,
+        end: f64,
+        step: f64,
+    },
     String(String),
-    Type(String),
+    /// An immutable sequence, built by `(a, b)` and taken apart by destructuring
+    Tuple(Vec<ObjectId>),
+    UserFunction {
+        name: String,
+        code_obj: Rc<CodeObj>,
+        param_names: Vec<String>,
+        variadic: bool,
+        class: Option<ObjectId>,
+        /// The cells captured from enclosing functions when this was built
+        upvalues: Vec<ObjectId>,
+        /// The module the function was defined in, which holds its globals
+        module: ObjectId,
+    },
+}
+
+impl HassiumObjectContext {
+    /// The name used for this kind of object in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            HassiumObjectContext::Bool(_) => "bool",
+            HassiumObjectContext::Cell(_) => "cell",
+            HassiumObjectContext::Class { .. } => "class",
+            HassiumObjectContext::Dict(_) => "dict",
+            HassiumObjectContext::Function(_) | HassiumObjectContext::UserFunction { .. } => {
+                "function"
+            }
+            HassiumObjectContext::Instance { .. } => "instance",
+            HassiumObjectContext::Iterator { .. } => "iterator",
+            HassiumObjectContext::List(_) => "list",
+            HassiumObjectContext::Module { .. } => "module",
+            HassiumObjectContext::None => "null",
+            HassiumObjectContext::Number(_) => "number",
+            HassiumObjectContext::Range { .. } => "range",
+            HassiumObjectContext::String(_) => "string",
+            HassiumObjectContext::Tuple(_) => "tuple",
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct HassiumObject {
     pub id: ObjectId,
     pub context: HassiumObjectContext,
-    pub self_ref: Box<Option<HassiumObject>>,
+    pub self_ref: Option<ObjectId>,
     pub attributes: HashMap<String, ObjectId>,
 }
 
-unsafe impl Sync for HassiumObject {}
-
 impl HassiumObject {
-    pub fn new(
+    /// Adds a new object to the heap, changes to it go through `heap.get_mut`
+    pub fn alloc(
         vm: &mut VMContext,
         context: HassiumObjectContext,
-        self_ref: Option<HassiumObject>,
-    ) -> HassiumObject {
-        unsafe {
-            let id = ObjectId(HASSIUM_OBJECT_ID);
-            let ret = HassiumObject {
-                id,
-                context,
-                attributes: HashMap::new(),
-                self_ref: Box::new(self_ref),
-            };
-            HASSIUM_OBJECT_ID = HASSIUM_OBJECT_ID.add(1);
-            println!("Inserting with id {}", id);
-            vm.all_objects.insert(id, Rc::new(Box::new(ret.clone())));
-            return ret;
-        }
+        self_ref: Option<ObjectId>,
+    ) -> ObjectId {
+        let id = vm.heap.next_id();
+        let obj = HassiumObject {
+            id,
+            context,
+            attributes: HashMap::new(),
+            self_ref,
+        };
+        vm.trace(|tracer| tracer.on_alloc(&obj));
+        vm.heap.insert(obj);
+        id
     }
 
-    pub fn getattr(&self, name: &str) -> ObjectId {
-        *self.attributes.get(name).unwrap()
+    pub fn getattr(&self, name: &str) -> Option<ObjectId> {
+        self.attributes.get(name).copied()
     }
 }
 
 impl ObjectId {
-    pub fn invoke(&self, vm: &mut VMContext, args: Vec<ObjectId>) -> ObjectId {
-        match vm.deref(*self).context {
-            HassiumObjectContext::Function(func) => func(vm, *self, args),
-            _ => panic!(""),
-        }
+    pub fn invoke(
+        &self,
+        vm: &mut VMContext,
+        args: Vec<ObjectId>,
+    ) -> Result<ObjectId, HassiumError> {
+        vm.call(*self, args)
     }
 }
@@ -0,0 +1,157 @@
+use crate::error::HassiumError;
+use crate::parser::ast::Align;
+use crate::parser::FormatSpec;
+use crate::runtime::object::defaults::stringify;
+use crate::runtime::object::{HassiumObjectContext, ObjectId};
+
+use super::VMContext;
+
+/// Pads `text` out to the width of `spec`
+fn pad(spec: &FormatSpec, text: String, is_number: bool) -> String {
+    let len = text.chars().count();
+    if len >= spec.width {
+        return text;
+    }
+    let padding = spec.width - len;
+    if spec.zero && is_number {
+        let (sign, digits) = match text.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", text.as_str()),
+        };
+        return format!("{}{}{}", sign, "0".repeat(padding), digits);
+    }
+    let fill = |count: usize| spec.fill.to_string().repeat(count);
+    let align = match spec.align {
+        Some(align) => align,
+        None if is_number => Align::Right,
+        None => Align::Left,
+    };
+    match align {
+        Align::Left => format!("{}{}", text, fill(padding)),
+        Align::Center => format!(
+            "{}{}{}",
+            fill(padding / 2),
+            text,
+            fill(padding - padding / 2)
+        ),
+        Align::Right => format!("{}{}", fill(padding), text),
+    }
+}
+
+impl VMContext {
+    /// The string `value` is formatted as by `spec`, only numbers take a precision
+    pub(super) fn format_value(
+        &mut self,
+        value: ObjectId,
+        spec: &FormatSpec,
+    ) -> Result<String, HassiumError> {
+        let number = match self.deref(value)?.context {
+            HassiumObjectContext::Number(number) => Some(number),
+            _ => None,
+        };
+        let text = match (number, spec.precision) {
+            (Some(number), Some(precision)) => format!("{:.*}", precision, number),
+            (None, Some(_)) => {
+                let type_name = self.deref(value)?.context.type_name();
+                return Err(HassiumError::type_error(format!(
+                    "Format precision needs a number, got {}!",
+                    type_name
+                )));
+            }
+            _ => stringify(self, value)?,
+        };
+        Ok(pad(spec, text, number.is_some()))
+    }
+
+    /// Joins the parts of an interpolated string, calling toString on every
+    /// part that is not a string yet
+    pub(super) fn interpolate(&mut self, parts: Vec<ObjectId>) -> Result<String, HassiumError> {
+        let mut ret: String = String::new();
+        for part in parts {
+            if let HassiumObjectContext::String(value) = &self.deref(part)?.context {
+                ret.push_str(value);
+                continue;
+            }
+            ret.push_str(&stringify(self, part)?);
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interpolate(code: &str) -> String {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            &format!(
+                "x = 3.14159;\nname = \"ab\";\nd = {{\"k\": [1, 2]}};\ns = {};",
+                code
+            ),
+            "test",
+        )
+        .unwrap();
+        let s = vm.get_global("s").unwrap();
+        stringify(&mut vm, s).unwrap()
+    }
+
+    fn interpolation_error(code: &str) -> HassiumError {
+        VMContext::new()
+            .run_source(&format!("x = 1;\ns = {};", code), "test")
+            .unwrap_err()
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(interpolate("$\"{{x}} is {x}, }}{{\""), "{x} is 3.14159, }{");
+        let error = interpolation_error("$\"a } b\"");
+        assert!(matches!(error, HassiumError::Lex { .. }));
+        assert_eq!(
+            error.to_string(),
+            "test:2:9: LexError: Unmatched \"}\" in interpolated string, write \"}}\" for a brace!"
+        );
+    }
+
+    #[test]
+    fn specs_fill_align_pad_and_round() {
+        assert_eq!(
+            interpolate(
+                "$\"[{x:.2}] [{x:8.3}] [{x:<8.1}] [{name:*^6}] [{name:>4}] [{name:4}] [{7:4}] \
+                 [{42:06}] [{-7:05}]\""
+            ),
+            "[3.14] [   3.142] [3.1     ] [**ab**] [  ab] [ab  ] [   7] [000042] [-0007]"
+        );
+    }
+
+    #[test]
+    fn invalid_specs_are_errors() {
+        let error = interpolation_error("$\"{x:8q}\"");
+        assert!(matches!(error, HassiumError::Syntax { .. }));
+        assert_eq!(
+            error.to_string(),
+            "test:2:10: SyntaxError: Invalid format spec \"8q\", \
+             expected [[fill]align][0][width][.precision]!"
+        );
+        let error = interpolation_error("$\"{'ab':.1}\"");
+        assert!(matches!(error, HassiumError::Type { .. }));
+        assert!(error
+            .message()
+            .starts_with("Format precision needs a number, got string!"));
+    }
+
+    #[test]
+    fn expressions_may_hold_brackets_and_strings() {
+        assert_eq!(
+            interpolate(
+                "$\"{d[\"k\"][(\"}\", 1)[1]]} {[1, {2: \"x}\"}][1][2]} {'a' + 'b'} {(1, \":\")[1]}\""
+            ),
+            "2 x} ab :"
+        );
+        let error = interpolation_error("$\"{\"a\"}\"");
+        assert_eq!(
+            error.to_string(),
+            "test:2:7: LexError: Unterminated expression in interpolated string!"
+        );
+    }
+}
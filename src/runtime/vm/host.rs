@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::emit::build_module;
+use crate::error::HassiumError;
+use crate::lexer::tokenize;
+use crate::parser::parse;
+use crate::runtime::object::convert::TypedFunction;
+use crate::runtime::object::defaults::{new_hassium_function, new_hassium_module};
+use crate::runtime::object::{Arity, ObjectId};
+
+use super::VMContext;
+
+impl VMContext {
+    /// Makes `value` visible to every module compiled for this VM afterwards.
+    /// Scripts can shadow it with a global of the same name
+    pub fn define_global(&mut self, name: &str, value: ObjectId) {
+        self.builtins.insert(name.to_string(), value);
+    }
+
+    /// Exposes a Rust function or closure to scripts as the global `name`. The
+    /// ids a closure captures are not garbage collection roots, `retain` any
+    /// object it holds on to or it may be freed between calls
+    pub fn define_function(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        func: impl Fn(&mut VMContext, ObjectId, Vec<ObjectId>) -> Result<ObjectId, HassiumError>
+            + 'static,
+    ) -> ObjectId {
+        let func = new_hassium_function(self, name, arity, func);
+        self.define_global(name, func);
+        func
+    }
+
+    /// Creates an empty module bound to the global `name`, fill it in with
+    /// define_module_function or set_attrib. Scripts can also `import` it
+    pub fn define_module(&mut self, name: &str) -> ObjectId {
+        let module = new_hassium_module(self, name.to_string());
+        self.define_global(name, module);
+        self.modules.insert(name.to_string(), module);
+        module
+    }
+
+    /// Compiles and runs `source` as part of the `__main__` module, so globals
+    /// defined by earlier calls stay visible. `file` names it in error locations
+    pub fn run_source(&mut self, source: &str, file: &str) -> Result<(), HassiumError> {
+        let tokens = tokenize(source.to_string(), file)?;
+        let module = build_module(parse(&tokens)?, self.builtin_names(), self.global_names())?;
+        self.run(Rc::new(module))
+    }
+
+    /// A global a script passed to `run` defined, None before anything ran
+    pub fn get_global(&self, name: &str) -> Option<ObjectId> {
+        let main_module = self.modules.get("__main__")?;
+        self.deref(*main_module).ok()?.attributes.get(name).copied()
+    }
+
+    /// Searches `path` for imported modules after the script's directory and
+    /// the directories listed in HASSIUM_PATH
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        self.search_paths.push(path.into());
+    }
+
+    /// Adds a Rust function to a module made by define_module, see define_function
+    pub fn define_module_function(
+        &mut self,
+        module: ObjectId,
+        name: &str,
+        arity: Arity,
+        func: impl Fn(&mut VMContext, ObjectId, Vec<ObjectId>) -> Result<ObjectId, HassiumError>
+            + 'static,
+    ) -> Result<ObjectId, HassiumError> {
+        let func = new_hassium_function(self, name, arity, func);
+        self.set_attrib(module, name, func)?;
+        Ok(func)
+    }
+
+    /// Exposes a Rust function taking and returning convertible values as the
+    /// global `name`, arguments are counted and converted before it is called
+    pub fn define_typed_function<Args: 'static>(
+        &mut self,
+        name: &str,
+        func: impl TypedFunction<Args> + 'static,
+    ) -> ObjectId {
+        let arity = func.arity();
+        let func_name = name.to_string();
+        self.define_function(name, arity, move |vm, _self_ref, args| {
+            func.call_typed(vm, &func_name, args)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use crate::error::HassiumError;
+    use crate::runtime::object::convert::FromHassium;
+    use crate::runtime::object::defaults::new_hassium_number;
+    use crate::runtime::object::Arity;
+    use crate::runtime::vm::VMContext;
+
+    fn number(vm: &mut VMContext, name: &str) -> f64 {
+        let id = vm.get_global(name).unwrap();
+        f64::from_hassium(vm, id).unwrap()
+    }
+
+    #[test]
+    fn closures_keep_their_state_between_calls() {
+        let mut vm = VMContext::new();
+        let count = Rc::new(Cell::new(0.0));
+        let counter = Rc::clone(&count);
+        vm.define_function("tick", Arity::Exact(0), move |vm, _self_ref, _args| {
+            counter.set(counter.get() + 1.0);
+            Ok(new_hassium_number(vm, counter.get()))
+        });
+        vm.run_source("a = tick(); b = tick();", "test").unwrap();
+        assert_eq!(number(&mut vm, "a"), 1.0);
+        assert_eq!(number(&mut vm, "b"), 2.0);
+        assert_eq!(count.get(), 2.0);
+    }
+
+    #[test]
+    fn calls_with_the_wrong_number_of_arguments_fail() {
+        let mut vm = VMContext::new();
+        vm.define_function(
+            "pair",
+            Arity::Exact(2),
+            |vm, _self_ref, _args| Ok(vm.null()),
+        );
+        let error = vm.run_source("x = 1;\npair(1);", "test").unwrap_err();
+        assert!(matches!(error, HassiumError::Type { .. }));
+        assert_eq!(
+            error.message(),
+            "pair() expected 2 arguments, got 1!\n    at <module> (test:2:5)"
+        );
+        assert_eq!(error.location().unwrap().to_string(), "test:2:5");
+    }
+
+    #[test]
+    fn typed_functions_convert_their_arguments() {
+        let mut vm = VMContext::new();
+        vm.define_typed_function("add", |a: f64, b: f64| Ok::<f64, HassiumError>(a + b));
+        vm.run_source("sum = add(2, 3);", "test").unwrap();
+        assert_eq!(number(&mut vm, "sum"), 5.0);
+
+        let error = vm.run_source("add(2, \"3\");", "test").unwrap_err();
+        assert!(matches!(error, HassiumError::Type { .. }));
+    }
+
+    #[test]
+    fn host_modules_expose_attributes_and_functions() {
+        let mut vm = VMContext::new();
+        let module = vm.define_module("host");
+        let version = new_hassium_number(&mut vm, 3.0);
+        vm.set_attrib(module, "version", version).unwrap();
+        vm.define_module_function(module, "double", Arity::Exact(1), |vm, _self_ref, args| {
+            let value = f64::from_hassium(vm, args[0])?;
+            Ok(new_hassium_number(vm, value * 2.0))
+        })
+        .unwrap();
+        vm.run_source(
+            "import host;\nv = host.version;\nd = host.double(4);",
+            "test",
+        )
+        .unwrap();
+        assert_eq!(number(&mut vm, "v"), 3.0);
+        assert_eq!(number(&mut vm, "d"), 8.0);
+    }
+
+    #[test]
+    fn callbacks_run_by_natives_collect_garbage() {
+        let mut vm = VMContext::new();
+        vm.define_function("repeat", Arity::Exact(2), |vm, _self_ref, args| {
+            let count = f64::from_hassium(vm, args[0])?;
+            for _ in 0..count as usize {
+                vm.call(args[1], Vec::new())?;
+            }
+            Ok(vm.null())
+        });
+        vm.run_source(
+            "func make() { return [1, 2, 3]; }\nrepeat(20000, make);",
+            "test",
+        )
+        .unwrap();
+        assert!(vm.heap_stats().collections > 0);
+    }
+
+    #[test]
+    fn globals_persist_between_runs() {
+        let mut vm = VMContext::new();
+        vm.run_source("x = 40;", "first").unwrap();
+        vm.run_source("y = x + 2;", "second").unwrap();
+        assert_eq!(number(&mut vm, "y"), 42.0);
+    }
+
+    #[test]
+    fn separate_vms_run_on_separate_threads() {
+        let barrier = Arc::new(Barrier::new(2));
+        let threads: Vec<_> = [3.0, 4.0]
+            .into_iter()
+            .map(|base| {
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let mut vm = VMContext::new();
+                    vm.define_function("base", Arity::Exact(0), move |vm, _self_ref, _args| {
+                        Ok(new_hassium_number(vm, base))
+                    });
+                    vm.run_source("total = 0;", "test").unwrap();
+                    barrier.wait();
+                    vm.run_source(
+                        "for (i = 0; i < 1000; i = i + 1) { total = total + base(); }",
+                        "test",
+                    )
+                    .unwrap();
+                    let total = number(&mut vm, "total");
+                    let error = vm.run_source("raise \"done\";", "test").unwrap_err();
+                    (total, error)
+                })
+            })
+            .collect();
+        let results: Vec<(f64, HassiumError)> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(results[0].0, 3000.0);
+        assert_eq!(results[1].0, 4000.0);
+        assert!(results
+            .iter()
+            .all(|(_, error)| error.to_string() == "test:1:1: RuntimeError: done"));
+    }
+}
@@ -1,14 +1,36 @@
+mod format;
+mod host;
+mod modules;
+mod ops;
+pub mod trace;
+
 use core::fmt;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::rc::Rc;
 
-use crate::parser::{BinOpType, UnaryOpType};
-use crate::runtime::object::defaults::{new_hassium_number, new_hassium_string};
-use crate::runtime::object::HassiumObject;
+use crate::error::{HassiumError, Span};
+use crate::parser::{BinOpType, FormatSpec, UnaryOpType};
+use crate::runtime::object::defaults::{
+    get_common_attribute, new_hassium_bound_method, new_hassium_cell, new_hassium_class,
+    new_hassium_dict, new_hassium_exception, new_hassium_instance, new_hassium_list,
+    new_hassium_module, new_hassium_number, new_hassium_string, new_hassium_tuple,
+    new_hassium_user_function, stringify,
+};
+use crate::runtime::object::dict::{dict_set, DictEntries};
+use crate::runtime::object::{HassiumObject, HassiumObjectContext};
 
-use super::object::defaults::get_defaults;
+use self::trace::Tracer;
+use super::object::defaults::define_defaults;
+use super::object::heap::{Heap, HeapStats};
 use super::object::ObjectId;
 
+/// How deeply calls may nest before a RuntimeError is raised. Frames live on
+/// the heap, but every call from native code back into the interpreter also
+/// recurses on the Rust stack, so those are held to a lower limit
+const MAX_FRAMES: usize = 1000;
+const MAX_NESTED_CALLS: usize = 200;
+
 #[derive(Debug)]
 pub enum VMInstruction {
     BinOp {
@@ -16,22 +38,64 @@ pub enum VMInstruction {
     },
     BuildClass {
         name: String,
-        code_obj: CodeObj,
+        code_obj: Rc<CodeObj>,
+        captures: Vec<Capture>,
         does_extend: bool,
     },
+    /// Pops `count` keys and values, pushed in pairs with the last value on top,
+    /// and pushes a dict of them
+    BuildDict {
+        count: u32,
+    },
     BuildFunc {
         name: String,
-        code_obj: CodeObj,
+        code_obj: Rc<CodeObj>,
+        captures: Vec<Capture>,
         param_names: Vec<String>,
+        variadic: bool,
         has_return_type: bool,
     },
-    Import,
+    /// Pops `count` items, the last one on top, and pushes a list of them
+    BuildList {
+        count: u32,
+    },
+    /// Pops the `count` parts of an interpolated string, the last one on top,
+    /// and pushes them joined into a string
+    BuildString {
+        count: u32,
+    },
+    /// Pops `count` items, the last one on top, and pushes a tuple of them
+    BuildTuple {
+        count: u32,
+    },
+    /// Pushes copies of the `count` values on top of the stack, in the same order
+    Dup {
+        count: u32,
+    },
+    /// Puts new empty cells in the captured slots from `from` up to `to`, run at
+    /// the start of each loop iteration so closures built in different
+    /// iterations don't share the variables declared in the loop
+    FreshCells {
+        from: u32,
+        to: u32,
+    },
+    /// Pushes the module loaded from the dotted `path`, running it first if it
+    /// has not been imported before
+    Import {
+        path: Vec<String>,
+    },
+    /// Replaces the value on top of the stack with the string it formats to
+    Format {
+        spec: FormatSpec,
+    },
     Invoke {
         arg_count: u32,
     },
+    /// Replaces the iterable on top of the stack with the result of its `iter`
     Iter,
+    /// Pops an iterator and pushes its next item, or jumps once `hasNext` is false
     IterNext {
-        jump_if_full: u32,
+        jump_if_done: u32,
     },
     Jump {
         to: u32,
@@ -42,9 +106,20 @@ pub enum VMInstruction {
     LoadAttrib {
         attrib: String,
     },
-    LoadId {
-        id: String,
+    LoadBool {
+        value: bool,
+    },
+    /// Loads the value held by the cell in a captured local slot
+    LoadCell {
+        slot: u32,
     },
+    LoadGlobal {
+        name: String,
+    },
+    LoadLocal {
+        slot: u32,
+    },
+    LoadNull,
     LoadNumber {
         value: f64,
     },
@@ -52,23 +127,47 @@ pub enum VMInstruction {
         value: String,
     },
     LoadSubscript,
+    LoadUpvalue {
+        index: u32,
+    },
     Pop,
     Raise,
+    /// Raises the exception a finally block caught again, keeping where it was
+    /// first raised
+    Reraise,
     Return,
+    /// Moves the value on top of the stack `count` places down
+    Rotate {
+        count: u32,
+    },
     SelfRef,
     StoreAttrib {
         attrib: String,
     },
-    StoreId {
-        id: String,
+    StoreCell {
+        slot: u32,
+    },
+    StoreGlobal {
+        name: String,
+    },
+    StoreLocal {
+        slot: u32,
     },
     StoreSubscript,
+    StoreUpvalue {
+        index: u32,
+    },
     Super {
         arg_count: u32,
     },
     UnaryOp {
         op: UnaryOpType,
     },
+    /// Pushes the `count` items of the tuple or list on top of the stack, which
+    /// stays below them, with the first item on top
+    Unpack {
+        count: u32,
+    },
 }
 
 impl fmt::Display for VMInstruction {
@@ -77,123 +176,1840 @@ impl fmt::Display for VMInstruction {
     }
 }
 
+/// Where a function gets each of its upvalues from when it is built, either a
+/// captured local slot or an upvalue of the function building it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    Local(u32),
+    Upvalue(u32),
+}
+
+/// A try block covering the instructions in `start..end`, exceptions raised
+/// there continue at `target` with the exception pushed onto the stack. The
+/// error is kept for finally blocks, which raise it again once they are done
+#[derive(Debug)]
+pub struct ExceptionHandler {
+    pub start: u32,
+    pub end: u32,
+    pub target: u32,
+    pub finally: bool,
+}
+
+/// Assembled bytecode, jump targets are instruction offsets. Only the emitter's
+/// assembler creates these and they are not changed afterwards
 #[derive(Debug)]
 pub struct CodeObj {
-    pub is_class: bool,
-    pub instructions: Vec<VMInstruction>,
-    pub labels: HashMap<u32, u32>,
+    instructions: Vec<VMInstruction>,
+    handlers: Vec<ExceptionHandler>,
+    /// Line table, the offset of the first instruction compiled from each span
+    spans: Vec<(u32, Span)>,
+    /// The name declared for each local slot, parameters come first
+    local_names: Vec<String>,
+    /// Local slots captured by nested functions, these hold cells
+    cell_slots: Vec<u32>,
 }
 
 impl CodeObj {
-    pub fn new(is_class: bool) -> CodeObj {
+    pub fn new(
+        instructions: Vec<VMInstruction>,
+        handlers: Vec<ExceptionHandler>,
+        spans: Vec<(u32, Span)>,
+        local_names: Vec<String>,
+        cell_slots: Vec<u32>,
+    ) -> CodeObj {
         CodeObj {
-            is_class,
-            instructions: Vec::new(),
-            labels: HashMap::new(),
+            instructions,
+            handlers,
+            spans,
+            local_names,
+            cell_slots,
         }
     }
+
+    /// Where in the source the instruction at `pos` was compiled from
+    pub fn span_at(&self, pos: u32) -> Option<&Span> {
+        let index = self.spans.partition_point(|(start, _)| *start <= pos);
+        index.checked_sub(1).map(|index| &self.spans[index].1)
+    }
 }
 
-#[derive(Clone)]
-pub struct VMContext {
-    pub all_objects: HashMap<ObjectId, Rc<Box<HassiumObject>>>,
-    stack_frame: Vec<HashMap<String, ObjectId>>,
+/// Decides what happens to a frame's return value once it finishes
+enum FrameKind {
+    Function,
+    ClassBody(ObjectId),
+    Constructor(ObjectId),
+    /// The top level of an imported module, which is cached once it finishes
+    Module(ObjectId),
+    Super,
+}
+
+struct CallFrame {
+    kind: FrameKind,
+    name: String,
+    code: Rc<CodeObj>,
     pos: u32,
+    stack: Vec<ObjectId>,
+    locals: Vec<Option<ObjectId>>,
+    /// Cells captured by the function or class body running in this frame
+    upvalues: Vec<ObjectId>,
+    self_ref: Option<ObjectId>,
+    class: Option<ObjectId>,
+    /// The module whose attributes are the globals of the running code
+    module: ObjectId,
+    /// Errors caught by finally blocks that are still running
+    finally_errors: Vec<HassiumError>,
+}
+
+impl CallFrame {
+    fn new(kind: FrameKind, name: String, code: Rc<CodeObj>, module: ObjectId) -> CallFrame {
+        CallFrame {
+            kind,
+            name,
+            module,
+            locals: vec![None; code.local_names.len()],
+            code,
+            pos: 0,
+            stack: Vec::new(),
+            upvalues: Vec::new(),
+            self_ref: None,
+            class: None,
+            finally_errors: Vec::new(),
+        }
+    }
+}
+
+pub struct VMContext {
+    pub heap: Heap,
+    builtins: HashMap<String, ObjectId>,
+    /// Imported modules by dotted name. Scripts passed to `run` share the
+    /// `__main__` module, its attributes are their globals
+    modules: HashMap<String, ObjectId>,
+    script_dir: Option<PathBuf>,
+    /// Directories the embedder added to the module search path
+    search_paths: Vec<PathBuf>,
+    frames: Vec<CallFrame>,
+    /// Objects the host or a running native function holds on to, these are
+    /// garbage collection roots until released
+    native_handles: Vec<ObjectId>,
+    /// Values the instructions being executed took off the stack. An instruction
+    /// may run user code before it is done with them, so they stay garbage
+    /// collection roots until it finishes
+    temporaries: Vec<ObjectId>,
+    /// Containers being printed and pairs of containers being compared. One met
+    /// again further down holds itself and is not walked a second time
+    printing: Vec<ObjectId>,
+    comparing: Vec<(ObjectId, ObjectId)>,
+    /// How many calls from native code into the interpreter are running
+    nested_calls: usize,
+    tracer: Option<Box<dyn Tracer>>,
+}
+
+impl Default for VMContext {
+    fn default() -> VMContext {
+        VMContext::new()
+    }
 }
 
 impl VMContext {
     pub fn new() -> VMContext {
         let mut ret = VMContext {
-            all_objects: HashMap::new(),
-            stack_frame: Vec::new(),
-            pos: 0,
+            heap: Heap::new(),
+            builtins: HashMap::new(),
+            modules: HashMap::new(),
+            script_dir: None,
+            search_paths: Vec::new(),
+            frames: Vec::new(),
+            native_handles: Vec::new(),
+            temporaries: Vec::new(),
+            printing: Vec::new(),
+            comparing: Vec::new(),
+            nested_calls: 0,
+            tracer: None,
         };
-        ret.stack_frame.push(get_defaults(&mut ret.clone()));
-
-        return ret;
-    }
-
-    pub fn deref(&self, id: ObjectId) -> &Rc<Box<HassiumObject>> {
-        println!(
-            "Does it contain {}, {}",
-            id,
-            self.all_objects.contains_key(&id)
-        );
-        self.all_objects.get(&id).unwrap()
-    }
-
-    pub fn run(&mut self, code: &CodeObj) {
-        let mut stack: Vec<ObjectId> = Vec::new();
-
-        while self.pos < code.instructions.len() as u32 {
-            let inst: &VMInstruction = code.instructions.get(self.pos as usize).unwrap();
-            println!("{}", inst);
-
-            match inst {
-                VMInstruction::BinOp { op } => todo!(),
-                VMInstruction::BuildClass {
-                    name,
-                    code_obj,
-                    does_extend,
-                } => todo!(),
-                VMInstruction::BuildFunc {
-                    name,
-                    code_obj,
-                    param_names,
-                    has_return_type,
-                } => todo!(),
-                VMInstruction::Import => todo!(),
-                VMInstruction::Invoke { arg_count } => {
-                    let obj = stack.pop().unwrap();
-                    let mut args: Vec<ObjectId> = Vec::new();
-                    for _i in 0..*arg_count {
-                        args.push(stack.pop().unwrap());
-                    }
-                    args.reverse();
-                    stack.push(obj.invoke(self, args))
-                }
-                VMInstruction::Iter => todo!(),
-                VMInstruction::IterNext { jump_if_full } => todo!(),
-                VMInstruction::Jump { to } => self.pos = *to,
-                VMInstruction::JumpIfFalse { to } => todo!(),
-                VMInstruction::LoadAttrib { attrib } => todo!(),
-                VMInstruction::LoadId { id } => {
-                    let mut found_id: bool = false;
-                    for frame in &self.stack_frame {
-                        let option = frame.get(id);
-                        if option.is_some() {
-                            stack.push(*option.unwrap());
-                            found_id = true;
-                            break;
+        define_defaults(&mut ret);
+
+        ret
+    }
+
+    pub fn deref(&self, id: ObjectId) -> Result<&Rc<HassiumObject>, HassiumError> {
+        self.heap
+            .get(id)
+            .ok_or_else(|| HassiumError::runtime_error(format!("Object {} does not exist!", id)))
+    }
+
+    pub fn builtin(&self, name: &str) -> ObjectId {
+        *self.builtins.get(name).unwrap()
+    }
+
+    /// The VM's `true` or `false` object, there is only one of each
+    pub fn bool(&self, value: bool) -> ObjectId {
+        self.builtin(if value { "true" } else { "false" })
+    }
+
+    pub fn null(&self) -> ObjectId {
+        self.builtin("null")
+    }
+
+    /// Starts sending execution events to `tracer`, replacing any previous one
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn trace(&mut self, event: impl FnOnce(&mut dyn Tracer)) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            event(tracer.as_mut());
+        }
+    }
+
+    /// Runs a module. An exception escaping it is returned with its stack trace
+    /// appended to the message
+    pub fn run(&mut self, code: Rc<CodeObj>) -> Result<(), HassiumError> {
+        let depth = self.frames.len();
+        let main_module = match self.modules.get("__main__") {
+            Some(main_module) => *main_module,
+            None => {
+                let main_module = new_hassium_module(self, "__main__".to_string());
+                self.modules.insert("__main__".to_string(), main_module);
+                main_module
+            }
+        };
+        self.enter_frame(CallFrame::new(
+            FrameKind::Function,
+            "<module>".to_string(),
+            code,
+            main_module,
+        ))?;
+        match self.execute(depth) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(self.describe_uncaught(error)),
+        }
+    }
+
+    /// Calls a Hassium object and runs it to completion, for use by native code.
+    /// Garbage may be collected while it runs, objects the caller made and still
+    /// needs afterwards have to be retained
+    pub fn call(
+        &mut self,
+        target: ObjectId,
+        args: Vec<ObjectId>,
+    ) -> Result<ObjectId, HassiumError> {
+        if self.nested_calls >= MAX_NESTED_CALLS {
+            return Err(HassiumError::runtime_error(format!(
+                "Maximum depth of {} calls from native code exceeded!",
+                MAX_NESTED_CALLS
+            )));
+        }
+        let depth = self.frames.len();
+        self.nested_calls += 1;
+        let ret = match self.call_object(target, args) {
+            Ok(Some(ret)) => Ok(ret),
+            Ok(None) => self.execute(depth),
+            Err(error) => Err(error),
+        };
+        self.nested_calls -= 1;
+        ret
+    }
+
+    /// Runs `print` to build the string for the container `id`, or returns None
+    /// when `id` is already being printed further up because it holds itself
+    pub fn print_container(
+        &mut self,
+        id: ObjectId,
+        print: impl FnOnce(&mut VMContext) -> Result<String, HassiumError>,
+    ) -> Result<Option<String>, HassiumError> {
+        if self.printing.contains(&id) {
+            return Ok(None);
+        }
+        self.printing.push(id);
+        let ret = print(self);
+        self.printing.pop();
+        ret.map(Some)
+    }
+
+    /// Keeps `id` alive across garbage collections until `release` is called,
+    /// for host code holding on to Hassium objects
+    pub fn retain(&mut self, id: ObjectId) {
+        self.native_handles.push(id);
+    }
+
+    pub fn release(&mut self, id: ObjectId) {
+        if let Some(index) = self.native_handles.iter().rposition(|handle| *handle == id) {
+            self.native_handles.remove(index);
+        }
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Runs a full collection, returning the number of objects freed
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut roots: Vec<ObjectId> = Vec::new();
+        roots.extend(self.builtins.values());
+        roots.extend(self.modules.values());
+        roots.extend(&self.native_handles);
+        roots.extend(&self.temporaries);
+        for frame in &self.frames {
+            roots.extend(&frame.stack);
+            roots.extend(frame.locals.iter().flatten());
+            roots.extend(&frame.upvalues);
+            roots.extend(frame.self_ref);
+            roots.extend(frame.class);
+            roots.push(frame.module);
+            match frame.kind {
+                FrameKind::ClassBody(id) | FrameKind::Constructor(id) | FrameKind::Module(id) => {
+                    roots.push(id)
+                }
+                FrameKind::Function | FrameKind::Super => (),
+            }
+        }
+        self.heap.collect(roots)
+    }
+
+    fn describe_uncaught(&mut self, error: HassiumError) -> HassiumError {
+        let exception = match error.exception() {
+            Some(exception) => exception,
+            None => return error,
+        };
+        let mut message = error.message().to_string();
+        if let Ok(Some(trace)) = self.get_attrib(exception, "trace") {
+            let items = match &self.deref(trace).map(|trace| trace.context.clone()) {
+                Ok(HassiumObjectContext::List(items)) => items.clone(),
+                _ => Vec::new(),
+            };
+            for item in items {
+                if let Ok(line) = stringify(self, item) {
+                    message.push_str(&format!("\n    {}", line));
+                }
+            }
+        }
+        error.with_message(message)
+    }
+
+    /// Turns a raised object into an error, the kind is taken from the built-in
+    /// exception class it inherits from
+    fn error_from_exception(&mut self, exception: ObjectId) -> HassiumError {
+        let message_id = self.get_attrib(exception, "message").ok().flatten();
+        let message = match stringify(self, message_id.unwrap_or(exception)) {
+            Ok(message) => message,
+            Err(_) => exception.to_string(),
+        };
+        let class = match self.deref(exception).map(|obj| obj.context.clone()) {
+            Ok(HassiumObjectContext::Instance { class }) => Some(class),
+            _ => None,
+        };
+        let error = if self.inherits_from(class, "NameError") {
+            HassiumError::name_error(message)
+        } else if self.inherits_from(class, "TypeError") {
+            HassiumError::type_error(message)
+        } else {
+            HassiumError::runtime_error(message)
+        };
+        error.with_exception(exception)
+    }
+
+    fn inherits_from(&self, class: Option<ObjectId>, builtin: &str) -> bool {
+        let builtin = self.builtin(builtin);
+        let mut current = class;
+        while let Some(class_id) = current {
+            if class_id == builtin {
+                return true;
+            }
+            current = match self.deref(class_id).map(|obj| &obj.context) {
+                Ok(HassiumObjectContext::Class { extends, .. }) => *extends,
+                _ => None,
+            };
+        }
+        false
+    }
+
+    /// Looks up an attribute on the object itself, then along its class' extends
+    /// chain. Functions found on a class are bound to the instance they were
+    /// accessed through
+    pub fn get_attrib(
+        &mut self,
+        target: ObjectId,
+        attrib: &str,
+    ) -> Result<Option<ObjectId>, HassiumError> {
+        let obj = Rc::clone(self.deref(target)?);
+        if let Some(value) = obj.getattr(attrib) {
+            return Ok(Some(value));
+        }
+        match &obj.context {
+            HassiumObjectContext::Class {
+                extends: Some(parent),
+                ..
+            } => match self.find_class_attrib(*parent, attrib)? {
+                Some(value) => Ok(Some(value)),
+                None => Ok(get_common_attribute(self, target, attrib)),
+            },
+            HassiumObjectContext::Class { extends: None, .. } => {
+                Ok(get_common_attribute(self, target, attrib))
+            }
+            HassiumObjectContext::Instance { class } => {
+                match self.find_class_attrib(*class, attrib)? {
+                    Some(value) => {
+                        let value_obj = Rc::clone(self.deref(value)?);
+                        match value_obj.context {
+                            HassiumObjectContext::Function(_)
+                            | HassiumObjectContext::UserFunction { .. } => {
+                                Ok(Some(new_hassium_bound_method(self, &value_obj, target)))
+                            }
+                            _ => Ok(Some(value)),
                         }
                     }
-                    if !found_id {
-                        panic!("ID {} could not be resolved!", id)
+                    None => Ok(get_common_attribute(self, target, attrib)),
+                }
+            }
+            _ => Ok(get_common_attribute(self, target, attrib)),
+        }
+    }
+
+    /// Looks up an attribute and calls it, for protocols such as iteration
+    pub fn call_attrib(
+        &mut self,
+        target: ObjectId,
+        attrib: &str,
+        args: Vec<ObjectId>,
+    ) -> Result<ObjectId, HassiumError> {
+        match self.get_attrib(target, attrib)? {
+            Some(func) => self.call(func, args),
+            None => Err(HassiumError::name_error(format!(
+                "Object {} has no attribute {}!",
+                target, attrib
+            ))),
+        }
+    }
+
+    /// Whether conditions treat `target` as true. Instances decide for themselves
+    /// when their class defines toBool, which has to return a bool
+    pub fn is_truthy(&mut self, target: ObjectId) -> Result<bool, HassiumError> {
+        if let HassiumObjectContext::Instance { class } = self.deref(target)?.context {
+            if self.find_class_attrib(class, "toBool")?.is_some() {
+                let value = self.call_attrib(target, "toBool", Vec::new())?;
+                return match self.deref(value)?.context {
+                    HassiumObjectContext::Bool(value) => Ok(value),
+                    ref context => Err(HassiumError::type_error(format!(
+                        "toBool must return a bool, got {}!",
+                        context.type_name()
+                    ))),
+                };
+            }
+        }
+        Ok(match &self.deref(target)?.context {
+            HassiumObjectContext::Bool(value) => *value,
+            HassiumObjectContext::Dict(entries) => !entries.is_empty(),
+            HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items) => {
+                !items.is_empty()
+            }
+            HassiumObjectContext::None => false,
+            HassiumObjectContext::Number(value) => *value != 0.0,
+            HassiumObjectContext::String(string) => !string.is_empty(),
+            _ => true,
+        })
+    }
+
+    fn find_class_attrib(
+        &self,
+        class: ObjectId,
+        attrib: &str,
+    ) -> Result<Option<ObjectId>, HassiumError> {
+        let mut current = Some(class);
+        while let Some(class_id) = current {
+            let class_obj = self.deref(class_id)?;
+            if let Some(value) = class_obj.getattr(attrib) {
+                return Ok(Some(value));
+            }
+            current = match &class_obj.context {
+                HassiumObjectContext::Class { extends, .. } => *extends,
+                _ => None,
+            };
+        }
+        Ok(None)
+    }
+
+    pub fn set_attrib(
+        &mut self,
+        target: ObjectId,
+        attrib: &str,
+        value: ObjectId,
+    ) -> Result<(), HassiumError> {
+        match self.heap.get_mut(target) {
+            Some(obj) => {
+                obj.attributes.insert(attrib.to_string(), value);
+                Ok(())
+            }
+            None => Err(HassiumError::runtime_error(format!(
+                "Object {} does not exist!",
+                target
+            ))),
+        }
+    }
+
+    /// Native functions return their result directly, user functions push a new
+    /// frame onto the call stack and return None
+    fn call_object(
+        &mut self,
+        target: ObjectId,
+        args: Vec<ObjectId>,
+    ) -> Result<Option<ObjectId>, HassiumError> {
+        self.call_object_as(FrameKind::Function, target, args)
+    }
+
+    fn call_object_as(
+        &mut self,
+        kind: FrameKind,
+        target: ObjectId,
+        args: Vec<ObjectId>,
+    ) -> Result<Option<ObjectId>, HassiumError> {
+        let obj = Rc::clone(self.deref(target)?);
+        match &obj.context {
+            HassiumObjectContext::Class { .. } => {
+                let instance = new_hassium_instance(self, target);
+                match self.find_class_attrib(target, "new")? {
+                    Some(constructor) => {
+                        let constructor = Rc::clone(self.deref(constructor)?);
+                        let bound = new_hassium_bound_method(self, &constructor, instance);
+                        self.call_object_as(FrameKind::Constructor(instance), bound, args)
                     }
+                    None => Ok(Some(instance)),
+                }
+            }
+            HassiumObjectContext::Function(func) => {
+                if !func.arity.accepts(args.len()) {
+                    return Err(HassiumError::type_error(format!(
+                        "{}() expected {} arguments, got {}!",
+                        func.name,
+                        func.arity,
+                        args.len()
+                    )));
+                }
+                let self_ref = obj.self_ref.unwrap_or(target);
+                let mut handles = args.clone();
+                handles.push(self_ref);
+                if let FrameKind::Constructor(instance) = kind {
+                    handles.push(instance);
+                }
+                for handle in &handles {
+                    self.retain(*handle);
                 }
-                VMInstruction::LoadNumber { value } => {
-                    let id = new_hassium_number(self, *value).id;
-                    stack.push(self.all_objects.get(&id).unwrap().id);
+                let depth = self.frames.len();
+                self.trace(|tracer| tracer.on_call(&func.name, depth));
+                let ret = (func.func)(self, self_ref, args);
+                for handle in handles {
+                    self.release(handle);
+                }
+                let value = ret.as_ref().ok().copied();
+                self.trace(|tracer| tracer.on_return(&func.name, depth, value));
+                let ret = ret?;
+                match kind {
+                    FrameKind::Constructor(instance) => Ok(Some(instance)),
+                    _ => Ok(Some(ret)),
+                }
+            }
+            HassiumObjectContext::UserFunction {
+                name,
+                code_obj,
+                param_names,
+                variadic,
+                class,
+                upvalues,
+                module,
+            } => {
+                let mut frame = CallFrame::new(kind, name.clone(), Rc::clone(code_obj), *module);
+                frame.upvalues = upvalues.clone();
+                frame.self_ref = obj.self_ref;
+                frame.class = *class;
+                self.push_frame(frame, name, param_names, *variadic, args)?;
+                Ok(None)
+            }
+            _ => Err(HassiumError::type_error(format!(
+                "Object {} is not callable!",
+                target
+            ))),
+        }
+    }
+
+    fn push_frame(
+        &mut self,
+        mut frame: CallFrame,
+        name: &str,
+        param_names: &[String],
+        variadic: bool,
+        mut args: Vec<ObjectId>,
+    ) -> Result<(), HassiumError> {
+        if args.len() < param_names.len() || (!variadic && args.len() > param_names.len()) {
+            return Err(HassiumError::type_error(format!(
+                "{}() expected {} arguments, got {}!",
+                name,
+                param_names.len(),
+                args.len()
+            )));
+        }
+
+        // The emitter gives parameters the first slots, followed by `args`
+        let extra_args = args.split_off(param_names.len());
+        for (slot, arg) in args.into_iter().enumerate() {
+            frame.locals[slot] = Some(arg);
+        }
+        if variadic {
+            let list = new_hassium_list(self, extra_args);
+            frame.locals[param_names.len()] = Some(list);
+        }
+        self.enter_frame(frame)
+    }
+
+    /// Pushes a frame once its arguments are in place, moving captured slots into
+    /// cells so closures built by the frame share them
+    fn enter_frame(&mut self, mut frame: CallFrame) -> Result<(), HassiumError> {
+        if self.frames.len() >= MAX_FRAMES {
+            return Err(HassiumError::runtime_error(format!(
+                "Maximum call depth of {} exceeded!",
+                MAX_FRAMES
+            )));
+        }
+        let code = Rc::clone(&frame.code);
+        for slot in &code.cell_slots {
+            let value = frame.locals[*slot as usize];
+            frame.locals[*slot as usize] = Some(new_hassium_cell(self, value));
+        }
+        let depth = self.frames.len();
+        self.trace(|tracer| tracer.on_call(&frame.name, depth));
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// The cells a function or class body being built in the current frame
+    /// captures, in the order the emitter numbered its upvalues
+    fn capture(&mut self, captures: &[Capture]) -> Vec<ObjectId> {
+        let frame = self.frame();
+        captures
+            .iter()
+            .map(|capture| match capture {
+                Capture::Local(slot) => frame.locals[*slot as usize].unwrap(),
+                Capture::Upvalue(index) => frame.upvalues[*index as usize],
+            })
+            .collect()
+    }
+
+    fn load_cell(&self, cell: ObjectId) -> Result<Option<ObjectId>, HassiumError> {
+        match self.deref(cell)?.context {
+            HassiumObjectContext::Cell(value) => Ok(value),
+            _ => Err(HassiumError::runtime_error(format!(
+                "Object {} is not a cell!",
+                cell
+            ))),
+        }
+    }
+
+    fn store_cell(&mut self, cell: ObjectId, value: ObjectId) -> Result<(), HassiumError> {
+        match self.heap.get_mut(cell) {
+            Some(obj) => {
+                obj.context = HassiumObjectContext::Cell(Some(value));
+                Ok(())
+            }
+            None => Err(HassiumError::runtime_error(format!(
+                "Object {} does not exist!",
+                cell
+            ))),
+        }
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn pop(&mut self) -> ObjectId {
+        let id = self.frame().stack.pop().unwrap();
+        self.temporaries.push(id);
+        id
+    }
+
+    /// Pops the top `count` values, in the order they were pushed
+    fn pop_many(&mut self, count: usize) -> Vec<ObjectId> {
+        let start = self.frame().stack.len() - count;
+        let items = self.frame().stack.split_off(start);
+        self.temporaries.extend(&items);
+        items
+    }
+
+    fn push(&mut self, id: ObjectId) {
+        self.frame().stack.push(id);
+    }
+
+    /// The names every module can refer to without defining them
+    pub fn builtin_names(&self) -> Vec<String> {
+        self.builtins.keys().cloned().collect()
+    }
+
+    /// The globals scripts passed to `run` so far have defined
+    pub fn global_names(&self) -> Vec<String> {
+        match self.modules.get("__main__") {
+            Some(main_module) => self
+                .heap
+                .get(*main_module)
+                .unwrap()
+                .attributes
+                .keys()
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Runs instructions until the call stack unwinds back to `depth` frames,
+    /// returning the value returned by the frame that was on top of it. An
+    /// exception that is not caught above `depth` is returned as the Err value
+    fn execute(&mut self, depth: usize) -> Result<ObjectId, HassiumError> {
+        loop {
+            match self.step(depth) {
+                Ok(Some(ret)) => return Ok(ret),
+                Ok(None) => (),
+                Err(error) => self.unwind(depth, error)?,
+            }
+        }
+    }
+
+    /// Unwinds the call stack to the nearest exception handler covering the
+    /// faulting instruction, stopping with Err once it would pop below `depth`.
+    /// Errors raised by the VM or by natives become exception objects here
+    fn unwind(&mut self, depth: usize, error: HassiumError) -> Result<(), HassiumError> {
+        let exception = match error.exception() {
+            Some(exception) => exception,
+            None => {
+                let class = match &error {
+                    HassiumError::Name { .. } => "NameError",
+                    HassiumError::Type { .. } => "TypeError",
+                    _ => "Exception",
+                };
+                new_hassium_exception(self, class, error.message().to_string())
+            }
+        };
+        let frame = self.frame();
+        let span = frame.code.span_at(frame.pos.saturating_sub(1)).cloned();
+        let error = error.with_exception(exception).with_location(span);
+        self.record_trace(exception)?;
+        self.trace(|tracer| tracer.on_exception(exception, &error));
+        loop {
+            let frame = self.frame();
+            let pos = frame.pos.saturating_sub(1);
+            let handler = frame
+                .code
+                .handlers
+                .iter()
+                .find(|handler| handler.start <= pos && pos < handler.end);
+            if let Some(handler) = handler {
+                frame.pos = handler.target;
+                frame.stack.clear();
+                frame.stack.push(exception);
+                if handler.finally {
+                    frame.finally_errors.push(error);
                 }
-                VMInstruction::LoadString { value } => {
-                    let id = &new_hassium_string(self, value.to_string()).id;
-                    stack.push(self.all_objects.get(id).unwrap().id);
+                return Ok(());
+            }
+            let frame = self.frames.pop().unwrap();
+            let frames = self.frames.len();
+            self.trace(|tracer| tracer.on_return(&frame.name, frames, None));
+            if self.frames.len() == depth {
+                return Err(error);
+            }
+        }
+    }
+
+    /// Where each frame is, innermost first
+    fn trace_lines(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .rev()
+            .map(
+                |frame| match frame.code.span_at(frame.pos.saturating_sub(1)) {
+                    Some(span) => format!("at {} ({})", frame.name, span),
+                    None => format!("at {}", frame.name),
+                },
+            )
+            .collect()
+    }
+
+    fn record_trace(&mut self, exception: ObjectId) -> Result<(), HassiumError> {
+        let obj = self.deref(exception)?;
+        if !matches!(obj.context, HassiumObjectContext::Instance { .. })
+            || obj.getattr("trace").is_some()
+        {
+            return Ok(());
+        }
+        let names = self.trace_lines();
+        let mut trace: Vec<ObjectId> = Vec::new();
+        for name in names {
+            trace.push(new_hassium_string(self, name));
+        }
+        let trace = new_hassium_list(self, trace);
+        self.set_attrib(exception, "trace", trace)
+    }
+
+    /// Executes a single instruction, returning the final value once the frame at
+    /// `depth` returns
+    fn step(&mut self, depth: usize) -> Result<Option<ObjectId>, HassiumError> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        let mark = self.temporaries.len();
+        let ret = self.execute_instruction(depth);
+        self.temporaries.truncate(mark);
+        ret
+    }
+
+    fn execute_instruction(&mut self, depth: usize) -> Result<Option<ObjectId>, HassiumError> {
+        let frame = self.frames.last_mut().unwrap();
+        if frame.pos >= frame.code.instructions.len() as u32 {
+            let none = self.null();
+            return self.return_from_frame(depth, none);
+        }
+        let code = Rc::clone(&frame.code);
+        let inst: &VMInstruction = code.instructions.get(frame.pos as usize).unwrap();
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.on_instruction(&frame.name, frame.pos, inst);
+        }
+        frame.pos += 1;
+
+        match inst {
+            VMInstruction::BinOp { op } => {
+                let left = self.pop();
+                let right = self.pop();
+                let value = self.binary_op(op, left, right)?;
+                self.push(value);
+            }
+            VMInstruction::BuildClass {
+                name,
+                code_obj,
+                captures,
+                does_extend,
+            } => {
+                let extends = if *does_extend { Some(self.pop()) } else { None };
+                let class = new_hassium_class(self, name.clone(), extends);
+                self.push(class);
+                let module = self.frame().module;
+                let mut frame = CallFrame::new(
+                    FrameKind::ClassBody(class),
+                    format!("class {}", name),
+                    Rc::clone(code_obj),
+                    module,
+                );
+                frame.upvalues = self.capture(captures);
+                frame.class = Some(class);
+                self.enter_frame(frame)?;
+            }
+            VMInstruction::BuildFunc {
+                name,
+                code_obj,
+                captures,
+                param_names,
+                variadic,
+                has_return_type,
+            } => {
+                if *has_return_type {
+                    self.pop();
                 }
-                VMInstruction::LoadSubscript => todo!(),
-                VMInstruction::Pop => {
-                    stack.pop();
+                let (class, module) = (self.frame().class, self.frame().module);
+                // Functions built inside a method keep its `self`
+                let self_ref = self.frame().self_ref;
+                let upvalues = self.capture(captures);
+                let func = new_hassium_user_function(
+                    self,
+                    name.clone(),
+                    Rc::clone(code_obj),
+                    param_names.clone(),
+                    *variadic,
+                    class,
+                    upvalues,
+                    module,
+                    self_ref,
+                );
+                self.push(func);
+            }
+            VMInstruction::BuildDict { count } => {
+                let items = self.pop_many(2 * *count as usize);
+                let dict = new_hassium_dict(self, DictEntries::default());
+                self.push(dict);
+                for pair in items.chunks(2) {
+                    dict_set(self, dict, pair[0], pair[1])?;
                 }
-                VMInstruction::Raise => todo!(),
-                VMInstruction::Return => todo!(),
-                VMInstruction::SelfRef => todo!(),
-                VMInstruction::StoreAttrib { attrib } => todo!(),
-                VMInstruction::StoreId { id } => todo!(),
-                VMInstruction::StoreSubscript => todo!(),
-                VMInstruction::Super { arg_count } => todo!(),
-                VMInstruction::UnaryOp { op } => todo!(),
             }
+            VMInstruction::BuildList { count } => {
+                let items = self.pop_many(*count as usize);
+                let list = new_hassium_list(self, items);
+                self.push(list);
+            }
+            VMInstruction::BuildString { count } => {
+                let parts = self.pop_many(*count as usize);
+                let string = self.interpolate(parts)?;
+                let string = new_hassium_string(self, string);
+                self.push(string);
+            }
+            VMInstruction::BuildTuple { count } => {
+                let items = self.pop_many(*count as usize);
+                let tuple = new_hassium_tuple(self, items);
+                self.push(tuple);
+            }
+            VMInstruction::Dup { count } => {
+                let stack = &mut self.frame().stack;
+                stack.extend_from_within(stack.len() - *count as usize..);
+            }
+            VMInstruction::Format { spec } => {
+                let value = self.pop();
+                let string = self.format_value(value, spec)?;
+                let string = new_hassium_string(self, string);
+                self.push(string);
+            }
+            VMInstruction::FreshCells { from, to } => {
+                let slots: Vec<u32> = code
+                    .cell_slots
+                    .iter()
+                    .copied()
+                    .filter(|slot| (*from..*to).contains(slot))
+                    .collect();
+                for slot in slots {
+                    let cell = new_hassium_cell(self, None);
+                    self.frame().locals[slot as usize] = Some(cell);
+                }
+            }
+            VMInstruction::Import { path } => {
+                if let Some(module) = self.import_module(path)? {
+                    self.push(module);
+                }
+            }
+            VMInstruction::Invoke { arg_count } => {
+                let obj = self.pop();
+                let mut args: Vec<ObjectId> = Vec::new();
+                for _i in 0..*arg_count {
+                    args.push(self.pop());
+                }
+                args.reverse();
+                if let Some(ret) = self.call_object(obj, args)? {
+                    self.push(ret);
+                }
+            }
+            VMInstruction::Iter => {
+                let target = self.pop();
+                if self.get_attrib(target, "iter")?.is_none() {
+                    return Err(HassiumError::type_error(format!(
+                        "Object {} is not iterable!",
+                        target
+                    )));
+                }
+                let iterator = self.call_attrib(target, "iter", Vec::new())?;
+                self.push(iterator);
+            }
+            VMInstruction::IterNext { jump_if_done } => {
+                let iterator = self.pop();
+                let has_next = self.call_attrib(iterator, "hasNext", Vec::new())?;
+                if self.is_truthy(has_next)? {
+                    let next = self.call_attrib(iterator, "next", Vec::new())?;
+                    self.push(next);
+                } else {
+                    self.frame().pos = *jump_if_done;
+                }
+            }
+            VMInstruction::Jump { to } => self.frame().pos = *to,
+            VMInstruction::JumpIfFalse { to } => {
+                let value = self.pop();
+                if !self.is_truthy(value)? {
+                    self.frame().pos = *to;
+                }
+            }
+            VMInstruction::LoadAttrib { attrib } => {
+                let target = self.pop();
+                match self.get_attrib(target, attrib)? {
+                    Some(value) => self.push(value),
+                    None => {
+                        return Err(HassiumError::name_error(format!(
+                            "Object {} has no attribute {}!",
+                            target, attrib
+                        )))
+                    }
+                }
+            }
+            VMInstruction::LoadBool { value } => {
+                let value = self.bool(*value);
+                self.push(value);
+            }
+            VMInstruction::LoadCell { slot } => {
+                let cell = self.frame().locals[*slot as usize].unwrap();
+                match self.load_cell(cell)? {
+                    Some(value) => self.push(value),
+                    None => {
+                        return Err(HassiumError::name_error(format!(
+                            "{} used before it was assigned!",
+                            code.local_names[*slot as usize]
+                        )))
+                    }
+                }
+            }
+            VMInstruction::LoadGlobal { name } => {
+                let module = self.frame().module;
+                let global = self.deref(module)?.getattr(name);
+                match global.or_else(|| self.builtins.get(name).copied()) {
+                    Some(value) => self.push(value),
+                    None => {
+                        return Err(HassiumError::name_error(format!(
+                            "{} is not defined!",
+                            name
+                        )))
+                    }
+                }
+            }
+            VMInstruction::LoadLocal { slot } => match self.frame().locals[*slot as usize] {
+                Some(value) => self.push(value),
+                None => {
+                    return Err(HassiumError::name_error(format!(
+                        "{} used before it was assigned!",
+                        code.local_names[*slot as usize]
+                    )))
+                }
+            },
+            VMInstruction::LoadNull => {
+                let null = self.null();
+                self.push(null);
+            }
+            VMInstruction::LoadNumber { value } => {
+                let id = new_hassium_number(self, *value);
+                self.push(id);
+            }
+            VMInstruction::LoadString { value } => {
+                let id = new_hassium_string(self, value.to_string());
+                self.push(id);
+            }
+            VMInstruction::LoadSubscript => {
+                let target = self.pop();
+                let key = self.pop();
+                let value = self.load_subscript(target, key)?;
+                self.push(value);
+            }
+            VMInstruction::LoadUpvalue { index } => {
+                let cell = self.frame().upvalues[*index as usize];
+                match self.load_cell(cell)? {
+                    Some(value) => self.push(value),
+                    None => {
+                        return Err(HassiumError::name_error(
+                            "Captured variable used before it was assigned!".to_string(),
+                        ))
+                    }
+                }
+            }
+            VMInstruction::Pop => {
+                self.pop();
+            }
+            VMInstruction::Raise => {
+                let exception = self.pop();
+                return Err(self.error_from_exception(exception));
+            }
+            VMInstruction::Reraise => {
+                let exception = self.pop();
+                let errors = &mut self.frame().finally_errors;
+                let error = match errors
+                    .iter()
+                    .rposition(|error| error.exception() == Some(exception))
+                {
+                    Some(index) => errors.remove(index),
+                    None => self.error_from_exception(exception),
+                };
+                return Err(error);
+            }
+            VMInstruction::Return => {
+                let value = self.pop();
+                return self.return_from_frame(depth, value);
+            }
+            VMInstruction::Rotate { count } => {
+                let stack = &mut self.frame().stack;
+                let value = stack.pop().unwrap();
+                stack.insert(stack.len() - *count as usize, value);
+            }
+            VMInstruction::SelfRef => match self.frame().self_ref {
+                Some(self_ref) => self.push(self_ref),
+                None => {
+                    return Err(HassiumError::runtime_error(
+                        "self used outside of a method!".to_string(),
+                    ))
+                }
+            },
+            VMInstruction::StoreAttrib { attrib } => {
+                let target = self.pop();
+                let value = *self.frame().stack.last().unwrap();
+                self.set_attrib(target, attrib, value)?;
+            }
+            VMInstruction::StoreCell { slot } => {
+                let frame = self.frame();
+                let cell = frame.locals[*slot as usize].unwrap();
+                let value = *frame.stack.last().unwrap();
+                self.store_cell(cell, value)?;
+            }
+            VMInstruction::StoreGlobal { name } => {
+                let frame = self.frame();
+                let (module, value) = (frame.module, *frame.stack.last().unwrap());
+                self.set_attrib(module, name, value)?;
+            }
+            VMInstruction::StoreLocal { slot } => {
+                let frame = self.frame();
+                frame.locals[*slot as usize] = frame.stack.last().copied();
+            }
+            VMInstruction::StoreSubscript => {
+                let target = self.pop();
+                let key = self.pop();
+                let value = *self.frame().stack.last().unwrap();
+                self.store_subscript(target, key, value)?;
+            }
+            VMInstruction::StoreUpvalue { index } => {
+                let frame = self.frame();
+                let cell = frame.upvalues[*index as usize];
+                let value = *frame.stack.last().unwrap();
+                self.store_cell(cell, value)?;
+            }
+            VMInstruction::Super { arg_count } => {
+                let mut args: Vec<ObjectId> = Vec::new();
+                for _i in 0..*arg_count {
+                    args.push(self.pop());
+                }
+                let frame = self.frame();
+                let (self_ref, class) = match (frame.self_ref, frame.class) {
+                    (Some(self_ref), Some(class)) => (self_ref, class),
+                    _ => {
+                        return Err(HassiumError::runtime_error(
+                            "super used outside of a method!".to_string(),
+                        ))
+                    }
+                };
+                let parent = match &self.deref(class)?.context {
+                    HassiumObjectContext::Class {
+                        extends: Some(parent),
+                        ..
+                    } => *parent,
+                    _ => {
+                        return Err(HassiumError::runtime_error(
+                            "super used in a class that does not extend another!".to_string(),
+                        ))
+                    }
+                };
+                let constructor = match self.find_class_attrib(parent, "new")? {
+                    Some(constructor) => Rc::clone(self.deref(constructor)?),
+                    None => {
+                        return Err(HassiumError::runtime_error(
+                            "Parent class has no constructor!".to_string(),
+                        ))
+                    }
+                };
+                let bound = new_hassium_bound_method(self, &constructor, self_ref);
+                self.call_object_as(FrameKind::Super, bound, args)?;
+            }
+            VMInstruction::UnaryOp { op } => {
+                let target = self.pop();
+                let value = self.unary_op(op, target)?;
+                self.push(value);
+            }
+            VMInstruction::Unpack { count } => {
+                let value = *self.frame().stack.last().unwrap();
+                let items = self.unpack(value, *count as usize)?;
+                self.frame().stack.extend(items.into_iter().rev());
+            }
+        }
+        Ok(None)
+    }
+
+    /// Pops the current frame and hands its return value to the caller. Returns
+    /// the value instead if this was the last frame `execute` was asked to run
+    fn return_from_frame(
+        &mut self,
+        depth: usize,
+        value: ObjectId,
+    ) -> Result<Option<ObjectId>, HassiumError> {
+        let frame = self.frames.pop().unwrap();
+        let value = match frame.kind {
+            FrameKind::Function => Some(value),
+            FrameKind::ClassBody(class) => {
+                // Temporaries are named `<...>` and are not class members
+                for (name, attrib) in frame.code.local_names.iter().zip(frame.locals) {
+                    if let (false, Some(attrib)) = (name.starts_with('<'), attrib) {
+                        self.set_attrib(class, name, attrib)?;
+                    }
+                }
+                None
+            }
+            FrameKind::Constructor(instance) => Some(instance),
+            FrameKind::Module(module) => {
+                self.finish_module(module)?;
+                Some(module)
+            }
+            FrameKind::Super => None,
+        };
+        let frames = self.frames.len();
+        self.trace(|tracer| tracer.on_return(&frame.name, frames, value));
+        if self.frames.len() == depth {
+            return Ok(Some(value.unwrap_or_else(|| self.null())));
+        }
+        if let Some(value) = value {
+            self.push(value);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::HassiumError;
+    use crate::runtime::object::defaults::stringify;
+    use crate::runtime::vm::VMContext;
+
+    fn run_error(code: &str) -> HassiumError {
+        VMContext::new().run_source(code, "test").unwrap_err()
+    }
+
+    #[test]
+    fn undefined_names_are_name_errors() {
+        let error = run_error("x = 1;\ny = x + z;");
+        assert!(matches!(error, HassiumError::Name { .. }));
+        assert_eq!(error.location().unwrap().to_string(), "test:2:9");
+        assert!(error.message().starts_with("z is not defined!"));
+    }
+
+    #[test]
+    fn unsupported_operands_are_type_errors() {
+        let error = run_error("x = 1 - \"a\";");
+        assert!(matches!(error, HassiumError::Type { .. }));
+        assert_eq!(error.location().unwrap().to_string(), "test:1:7");
+        assert_eq!(
+            error.message(),
+            "Unsupported operand types for Subtract: number and string!\n    at <module> (test:1:7)"
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_a_runtime_error() {
+        let error = run_error("func f(x) {\n    return x / 0;\n}\nf(1);");
+        assert!(matches!(error, HassiumError::Runtime { .. }));
+        assert_eq!(error.location().unwrap().to_string(), "test:2:14");
+        assert_eq!(
+            error.message(),
+            "Division by zero!\n    at f (test:2:14)\n    at <module> (test:4:2)"
+        );
+    }
+
+    #[test]
+    fn collecting_inside_callbacks_keeps_operands_alive() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "class P {
+                func new(x) { self.x = x; }
+                func hash() { gc(); return self.x; }
+                func equals(other) { return self.x == other.x; }
+            }
+            d = {P(1): [1], P(2): [2], P(3): [3]};
+            total = d[P(1)][0] + d[P(2)][0] + d[P(3)][0];",
+            "test",
+        )
+        .unwrap();
+        let total = vm.get_global("total").unwrap();
+        assert_eq!(stringify(&mut vm, total).unwrap(), "6");
+    }
+
+    #[test]
+    fn containers_that_hold_themselves_compare_by_contents() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "a = [1];\na.add(a);\nb = [1];\nb.add(b);\nc = [2];\nc.add(c);
+            same = a == b;\ndifferent = a == c;",
+            "test",
+        )
+        .unwrap();
+        let same = vm.get_global("same").unwrap();
+        let different = vm.get_global("different").unwrap();
+        assert_eq!(stringify(&mut vm, same).unwrap(), "true");
+        assert_eq!(stringify(&mut vm, different).unwrap(), "false");
+    }
+
+    #[test]
+    fn runaway_recursion_raises_a_catchable_error() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "message = null;\ndepth = 0;\nfunc f(n) { return f(n + 1); }
+            try { f(0); } catch (e) { message = e.message; depth = e.trace.length(); }",
+            "test",
+        )
+        .unwrap();
+        let message = vm.get_global("message").unwrap();
+        let depth = vm.get_global("depth").unwrap();
+        assert_eq!(
+            stringify(&mut vm, message).unwrap(),
+            "Maximum call depth of 1000 exceeded!"
+        );
+        assert_eq!(stringify(&mut vm, depth).unwrap(), "1000");
+
+        let error = run_error("func f(n) {\n    return f(n + 1);\n}\nf(0);");
+        assert!(matches!(error, HassiumError::Runtime { .. }));
+        assert_eq!(error.location().unwrap().to_string(), "test:2:13");
+    }
+
+    #[test]
+    fn runaway_recursion_through_natives_raises_an_error() {
+        // Every level recurses on the Rust stack, which is small on test threads
+        let thread = std::thread::Builder::new().stack_size(64 << 20).spawn(|| {
+            let error = run_error(
+                "class A {
+                    func new(n) { self.n = n; }
+                    func toString() { return \"x\" + A(self.n + 1); }
+                }
+                s = \"\" + A(0);",
+            );
+            assert!(matches!(error, HassiumError::Runtime { .. }));
+            error.message().to_string()
+        });
+        let message = thread.unwrap().join().unwrap();
+        assert!(message.starts_with("Maximum depth of 200 calls from native code exceeded!"));
+    }
+
+    #[test]
+    fn bitwise_operators_reject_numbers_that_do_not_fit() {
+        let mut vm = VMContext::new();
+        vm.run_source("a = -1 & 255;\nb = 0x8000000000000000 | 2048;", "test")
+            .unwrap();
+        let a = vm.get_global("a").unwrap();
+        let b = vm.get_global("b").unwrap();
+        assert_eq!(stringify(&mut vm, a).unwrap(), "255");
+        assert_eq!(stringify(&mut vm, b).unwrap(), "9223372036854778000");
+
+        let error = run_error("x = 1e30 ^ 1;");
+        assert!(matches!(error, HassiumError::Runtime { .. }));
+        assert!(error.message().starts_with(
+            "Xor needs whole numbers that fit in 64 bits, got 1000000000000000000000000000000!"
+        ));
+        let error = run_error("x = 1.5 & 1;");
+        assert!(error
+            .message()
+            .starts_with("BitwiseAnd needs whole numbers that fit in 64 bits, got 1.5!"));
+    }
+
+    #[test]
+    fn bitwise_operands_use_the_signed_or_unsigned_range() {
+        let values = run_globals(
+            "a = 1e19 | 1;\nb = -0x8000000000000000 & -1;\nc = 0x8000000000000000 ^ 1;",
+            &["a", "b", "c"],
+        );
+        assert_eq!(
+            values,
+            [
+                "10000000000000000000",
+                "-9223372036854776000",
+                "9223372036854776000"
+            ]
+        );
+        for (code, message) in [
+            (
+                "x = -1 & 1e19;",
+                "BitwiseAnd needs whole numbers that fit in 64 bits, got 10000000000000000000!",
+            ),
+            (
+                "x = 2e19 | 1;",
+                "BitwiseOr needs whole numbers that fit in 64 bits, got 20000000000000000000!",
+            ),
+            (
+                "x = -1e19 ^ 1;",
+                "Xor needs whole numbers that fit in 64 bits, got -10000000000000000000!",
+            ),
+        ] {
+            let error = run_error(code);
+            assert!(matches!(error, HassiumError::Runtime { .. }));
+            assert!(error.message().starts_with(message), "{}", code);
+        }
+    }
+
+    #[test]
+    fn runtime_errors_can_be_caught() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "message = null;\ntry { x = [1][5]; } catch (e) { message = e.message; }",
+            "test",
+        )
+        .unwrap();
+        let message = vm.get_global("message").unwrap();
+        assert_eq!(
+            stringify(&mut vm, message).unwrap(),
+            "Index 5 out of range for length 1!"
+        );
+    }
+
+    #[test]
+    fn negation_is_checked_and_can_be_overloaded() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "class V {
+                func new(x) { self.x = x; }
+                func __neg__() { return V(-self.x); }
+            }
+            a = -(3 - 5);
+            b = (-V(4)).x;",
+            "test",
+        )
+        .unwrap();
+        let a = vm.get_global("a").unwrap();
+        let b = vm.get_global("b").unwrap();
+        assert_eq!(stringify(&mut vm, a).unwrap(), "2");
+        assert_eq!(stringify(&mut vm, b).unwrap(), "-4");
+
+        let error = run_error("x = -[1, 2];");
+        assert!(matches!(error, HassiumError::Type { .. }));
+        assert!(error
+            .message()
+            .starts_with("Unsupported operand type for Negate: list!"));
+        let error = run_error("x = [1] * {1: 2};");
+        assert!(error
+            .message()
+            .starts_with("Unsupported operand types for Multiply: list and dict!"));
+    }
+
+    #[test]
+    fn subscripts_read_from_every_indexable_type() {
+        let mut vm = VMContext::new();
+        vm.run_source(
+            "l = [1, 2, 3];
+            t = (4, 5);
+            d = {\"a\": 6};
+            s = \"héllo\";
+            out = l[-1] + t[0] + d[\"a\"];
+            c = s[1];",
+            "test",
+        )
+        .unwrap();
+        let out = vm.get_global("out").unwrap();
+        let c = vm.get_global("c").unwrap();
+        assert_eq!(stringify(&mut vm, out).unwrap(), "13");
+        assert_eq!(stringify(&mut vm, c).unwrap(), "é");
+
+        let error = run_error("x = (1, 2)[2];");
+        assert!(error
+            .message()
+            .starts_with("Index 2 out of range for length 2!"));
+        let error = run_error("x = {1: 2}[3];");
+        assert!(error.message().starts_with("Key 3 not found!"));
+        let error = run_error("x = 5[0];");
+        assert!(matches!(error, HassiumError::Type { .. }));
+        assert!(error
+            .message()
+            .starts_with("Object of type number is not subscriptable!"));
+    }
+
+    fn run_globals(code: &str, names: &[&str]) -> Vec<String> {
+        let mut vm = VMContext::new();
+        vm.run_source(code, "test").unwrap();
+        names
+            .iter()
+            .map(|name| {
+                let value = vm.get_global(name).unwrap();
+                stringify(&mut vm, value).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn closures_update_variables_after_their_function_returns() {
+        let values = run_globals(
+            "func counter() {
+                count = 0;
+                func next() {
+                    count = count + 1;
+                    return count;
+                }
+                return next;
+            }
+            a = counter();
+            b = counter();
+            a();
+            a();
+            x = a();
+            y = b();",
+            &["x", "y"],
+        );
+        assert_eq!(values, ["3", "1"]);
+    }
+
+    #[test]
+    fn closures_capture_through_two_levels() {
+        let values = run_globals(
+            "func outer(x) {
+                func middle() {
+                    func inner() {
+                        x = x * 2;
+                        return x;
+                    }
+                    return inner;
+                }
+                f = middle();
+                f();
+                return f;
+            }
+            f = outer(5);
+            x = f();",
+            &["x"],
+        );
+        assert_eq!(values, ["20"]);
+    }
+
+    #[test]
+    fn closures_inside_methods_keep_self() {
+        let values = run_globals(
+            "class Box {
+                func new(n) { self.n = n; }
+                func adder() {
+                    func add(k) {
+                        func bump() { self.n = self.n + k; }
+                        bump();
+                        return self.n;
+                    }
+                    return add;
+                }
+            }
+            b = Box(3);
+            add = b.adder();
+            add(4);
+            x = add(1);
+            n = b.n;",
+            &["x", "n"],
+        );
+        assert_eq!(values, ["8", "8"]);
+    }
+
+    #[test]
+    fn closures_built_in_a_loop_keep_their_own_variables() {
+        let values = run_globals(
+            "fs = [];
+            foreach (i in [1, 2, 3]) {
+                func get() { return i; }
+                fs.add(get);
+            }
+            j = 0;
+            while (j < 3) {
+                k = j * 10;
+                func getk() { return k; }
+                fs.add(getk);
+                j = j + 1;
+            }
+            out = [];
+            foreach (f in fs) { out.add(f()); }",
+            &["out"],
+        );
+        assert_eq!(values, ["[1, 2, 3, 0, 10, 20]"]);
+    }
+
+    #[test]
+    fn constructors_set_up_instances() {
+        let values = run_globals(
+            "class Point {
+                func new(x, y) {
+                    self.x = x;
+                    self.y = y;
+                }
+                func sum() { return self.x + self.y; }
+            }
+            p = Point(2, 5);
+            q = Point(1, 1);
+            p.x = 10;
+            sum = p.sum();
+            other = q.sum();",
+            &["sum", "other"],
+        );
+        assert_eq!(values, ["15", "2"]);
+    }
+
+    #[test]
+    fn classes_inherit_and_override_methods() {
+        let values = run_globals(
+            "class Animal {
+                func new(name) { self.name = name; }
+                func speak() { return self.name + \" makes a sound\"; }
+                func kind() { return \"animal\"; }
+            }
+            class Dog extends Animal {
+                func kind() { return \"dog\"; }
+            }
+            d = Dog(\"Rex\");
+            speak = d.speak();
+            kind = d.kind();
+            base = Animal(\"Cat\").kind();",
+            &["speak", "kind", "base"],
+        );
+        assert_eq!(values, ["Rex makes a sound", "dog", "animal"]);
+    }
+
+    #[test]
+    fn super_passes_arguments_up_the_chain() {
+        let values = run_globals(
+            "class Animal {
+                func new(name) { self.name = name; }
+            }
+            class Dog extends Animal {
+                func new(name, breed) {
+                    super(name);
+                    self.breed = breed;
+                }
+            }
+            class Puppy extends Dog {
+                func new(name) { super(name, \"mutt\"); }
+            }
+            p = Puppy(\"Bit\");
+            name = p.name;
+            breed = p.breed;",
+            &["name", "breed"],
+        );
+        assert_eq!(values, ["Bit", "mutt"]);
+
+        let error = run_error("class A {\n    func f() { super(); }\n}\nA().f();");
+        assert!(matches!(error, HassiumError::Runtime { .. }));
+        assert_eq!(error.location().unwrap().to_string(), "test:2:16");
+        assert!(error
+            .message()
+            .starts_with("super used in a class that does not extend another!"));
+    }
+
+    #[test]
+    fn missing_attributes_are_errors() {
+        let error = run_error("class A {\n    func new() { self.x = 1; }\n}\ny = A().missing;");
+        assert!(matches!(error, HassiumError::Name { .. }));
+        assert_eq!(error.location().unwrap().to_string(), "test:4:8");
+        assert!(error
+            .message()
+            .contains("has no attribute missing!\n    at <module> (test:4:8)"));
+        let error = run_error("class A {}\nA().f();");
+        assert!(error.message().contains("has no attribute f!"));
+    }
+
+    #[test]
+    fn try_catch_and_finally_run_in_order() {
+        let values = run_globals(
+            "log = [];
+            func f() {
+                try {
+                    log.add(\"try\");
+                    raise Exception(\"boom\");
+                    log.add(\"skipped\");
+                } catch (e) {
+                    log.add(\"catch \" + e.message);
+                } finally {
+                    log.add(\"finally\");
+                }
+                log.add(\"after\");
+            }
+            func g() {
+                for (i = 0; i < 3; i = i + 1) {
+                    try {
+                        if (i == 1) { continue; }
+                        if (i == 2) { return \"return\"; }
+                        log.add(\"body \" + i);
+                    } finally {
+                        log.add(\"finally \" + i);
+                    }
+                }
+            }
+            f();
+            log.add(g());
+            try {
+                try { x = 1 / 0; } finally { log.add(\"unwinding\"); }
+            } catch (e) {
+                log.add(\"caught \" + e.message);
+            }",
+            &["log"],
+        );
+        assert_eq!(
+            values,
+            [
+                "[try, catch boom, finally, after, body 0, finally 0, finally 1, finally 2, \
+              return, unwinding, caught Division by zero!]"
+            ]
+        );
+    }
+
+    #[test]
+    fn rethrown_exceptions_keep_their_trace() {
+        let code = "message = null;
+trace = null;
+func inner() {
+    raise Exception(\"deep\");
+}
+func outer() {
+    try {
+        inner();
+    } catch (e) {
+        raise e;
+    }
+}
+try {
+    outer();
+} catch (e) {
+    message = e.message;
+    trace = e.trace;
+}
+outer();";
+        let mut vm = VMContext::new();
+        let error = vm.run_source(code, "test").unwrap_err();
+        let message = vm.get_global("message").unwrap();
+        let trace = vm.get_global("trace").unwrap();
+        assert_eq!(stringify(&mut vm, message).unwrap(), "deep");
+        assert_eq!(
+            stringify(&mut vm, trace).unwrap(),
+            "[at inner (test:4:5), at outer (test:8:14), at <module> (test:14:10)]"
+        );
+        assert!(matches!(error, HassiumError::Runtime { .. }));
+        assert_eq!(
+            error.message(),
+            "deep\n    at inner (test:4:5)\n    at outer (test:8:14)\n    at <module> (test:19:6)"
+        );
+    }
+
+    #[test]
+    fn uncaught_exceptions_carry_where_they_were_raised() {
+        let error = run_error(
+            "class MyError extends Exception {}
+func fail() {
+    try {
+        raise MyError(\"failed\");
+    } finally {
+        x = 1;
+    }
+}
+fail();",
+        );
+        assert!(matches!(error, HassiumError::Runtime { .. }));
+        assert_eq!(error.location().unwrap().to_string(), "test:4:9");
+        assert_eq!(
+            error.to_string(),
+            "test:4:9: RuntimeError: failed\n    at fail (test:4:9)\n    at <module> (test:9:5)"
+        );
+        let error = run_error("x = 1;\n\nraise \"plain\";");
+        assert_eq!(error.location().unwrap().to_string(), "test:3:1");
+        assert_eq!(error.message(), "plain");
+    }
+
+    #[test]
+    fn foreach_uses_the_iterator_protocol_of_user_classes() {
+        let values = run_globals(
+            "class Pairs {
+                func new(items) { self.items = items; }
+                func iter() { return PairIterator(self.items); }
+            }
+            class PairIterator {
+                func new(items) {
+                    self.items = items;
+                    self.index = 0;
+                }
+                func hasNext() { return self.index < self.items.length(); }
+                func next() {
+                    self.index = self.index + 1;
+                    return (self.index, self.items[self.index - 1]);
+                }
+            }
+            out = [];
+            pairs = Pairs([\"a\", \"b\", \"c\"]);
+            foreach ((i, item) in pairs) {
+                out.add(item + i);
+            }
+            foreach (_ in pairs) {
+                out.add(\"again\");
+                break;
+            }",
+            &["out"],
+        );
+        assert_eq!(values, ["[a1, b2, c3, again]"]);
+    }
+
+    #[test]
+    fn iterators_raising_partway_stop_the_loop() {
+        let code = "class Countdown {
+    func new(n) { self.n = n; }
+    func iter() { return self; }
+    func hasNext() { return self.n > 0; }
+    func next() {
+        self.n = self.n - 1;
+        if (self.n == 1) { raise Exception(\"stopped\"); }
+        return self.n;
+    }
+}
+seen = [];
+message = null;
+try {
+    foreach (x in Countdown(4)) { seen.add(x); }
+} catch (e) {
+    message = e.message;
+}
+foreach (x in Countdown(4)) { seen.add(x); }";
+        let mut vm = VMContext::new();
+        let error = vm.run_source(code, "test").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "test:7:28: RuntimeError: stopped\n    at next (test:7:28)\n    at <module> (test:18:1)"
+        );
+        let seen = vm.get_global("seen").unwrap();
+        let message = vm.get_global("message").unwrap();
+        assert_eq!(stringify(&mut vm, seen).unwrap(), "[3, 2, 3, 2]");
+        assert_eq!(stringify(&mut vm, message).unwrap(), "stopped");
+
+        let error = run_error("foreach (x in 5) {}");
+        assert!(matches!(error, HassiumError::Type { .. }));
+        assert!(error.message().contains("is not iterable!"));
+    }
+
+    #[test]
+    fn conditions_use_to_bool() {
+        let values = run_globals(
+            "class Flag {
+                func new(on) { self.on = on; }
+                func toBool() { return self.on; }
+            }
+            class Countdown {
+                func new(n) { self.n = n; }
+                func toBool() {
+                    self.n = self.n - 1;
+                    return self.n >= 0;
+                }
+            }
+            taken = [];
+            if (Flag(true)) { taken.add(\"if\"); }
+            if (Flag(false)) { taken.add(\"wrong\"); } else { taken.add(\"else\"); }
+            loops = 0;
+            c = Countdown(3);
+            while (c) { loops = loops + 1; }
+            both = Flag(true) && Flag(false);
+            either = Flag(false) || Flag(true);
+            negated = !Flag(false);
+            builtins = (!0, ![], !\"\", !null, [0] && {1: 2});",
+            &["taken", "loops", "both", "either", "negated", "builtins"],
+        );
+        assert_eq!(
+            values,
+            [
+                "[if, else]",
+                "3",
+                "false",
+                "true",
+                "true",
+                "(true, true, true, true, true)"
+            ]
+        );
+    }
 
-            self.pos += 1;
+    #[test]
+    fn to_bool_must_return_a_bool() {
+        let code = "class Odd {\n    func toBool() { return 1; }\n}\n";
+        for condition in [
+            "if (Odd()) {}",
+            "while (Odd()) {}",
+            "x = Odd() && true;",
+            "x = false || Odd();",
+            "x = !Odd();",
+        ] {
+            let error = run_error(&format!("{}{}", code, condition));
+            assert!(matches!(error, HassiumError::Type { .. }));
+            assert!(
+                error
+                    .message()
+                    .starts_with("toBool must return a bool, got number!"),
+                "{}",
+                condition
+            );
         }
     }
 }
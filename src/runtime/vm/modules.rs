@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::emit::build_module;
+use crate::error::HassiumError;
+use crate::lexer::tokenize;
+use crate::parser::parse;
+use crate::runtime::object::defaults::new_hassium_module;
+use crate::runtime::object::{HassiumObjectContext, ObjectId};
+
+use super::{CallFrame, FrameKind, VMContext};
+
+impl VMContext {
+    /// The directory of the script being run, searched first for imports
+    pub fn set_script_dir(&mut self, dir: &Path) {
+        self.script_dir = Some(dir.to_path_buf());
+    }
+
+    /// Where `import` looks for files, in order: the script's directory, each
+    /// directory in HASSIUM_PATH, then the directories the embedder added
+    fn module_search_path(&self) -> Vec<PathBuf> {
+        let mut ret: Vec<PathBuf> = self.script_dir.iter().cloned().collect();
+        if let Some(paths) = std::env::var_os("HASSIUM_PATH") {
+            ret.extend(std::env::split_paths(&paths));
+        }
+        ret.extend(self.search_paths.iter().cloned());
+        ret
+    }
+
+    /// `a.b.c` is found at `a/b/c.has` under the first directory containing it
+    fn resolve_module(&self, path: &[String]) -> Result<PathBuf, HassiumError> {
+        let mut relative: PathBuf = path.iter().collect();
+        relative.set_extension("has");
+        let search_path = self.module_search_path();
+        for dir in &search_path {
+            let file = dir.join(&relative);
+            if file.is_file() {
+                return Ok(file);
+            }
+        }
+        let searched: Vec<String> = search_path
+            .iter()
+            .map(|dir| format!("\"{}\"", dir.display()))
+            .collect();
+        Err(HassiumError::runtime_error(format!(
+            "Could not find module {} ({}) in [{}]!",
+            path.join("."),
+            relative.display(),
+            searched.join(", ")
+        )))
+    }
+
+    /// The names of the modules currently being run by imports, outermost first
+    fn importing(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .filter_map(|frame| match frame.kind {
+                FrameKind::Module(module) => match &self.deref(module).ok()?.context {
+                    HassiumObjectContext::Module { name } => Some(name.clone()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns a module that was already imported. Otherwise its file is compiled
+    /// and a frame running it is pushed, which hands the module to the importer
+    /// and caches it once it finishes
+    pub(super) fn import_module(
+        &mut self,
+        path: &[String],
+    ) -> Result<Option<ObjectId>, HassiumError> {
+        let name = path.join(".");
+        if let Some(module) = self.modules.get(&name) {
+            return Ok(Some(*module));
+        }
+        let mut importing = self.importing();
+        if let Some(start) = importing.iter().position(|module| *module == name) {
+            importing.push(name);
+            return Err(HassiumError::runtime_error(format!(
+                "Circular import: {}!",
+                importing[start..].join(" -> ")
+            )));
+        }
+
+        let file = self.resolve_module(path)?;
+        let source = std::fs::read_to_string(&file).map_err(|error| {
+            HassiumError::runtime_error(format!("Could not read {}: {}!", file.display(), error))
+        })?;
+        // Modules only share the built-ins, their globals are their own. Compile
+        // errors keep their kind and location in the module, followed by where
+        // it was imported from
+        let code = tokenize(source, &file.display().to_string())
+            .and_then(|tokens| parse(&tokens))
+            .and_then(|ast| build_module(ast, self.builtin_names(), Vec::new()))
+            .map_err(|error| {
+                let mut message = error.message().to_string();
+                for line in self.trace_lines() {
+                    message.push_str(&format!("\n    {}", line));
+                }
+                error.with_message(message)
+            })?;
+
+        let module = new_hassium_module(self, name.clone());
+        self.enter_frame(CallFrame::new(
+            FrameKind::Module(module),
+            format!("<module {}>", name),
+            Rc::new(code),
+            module,
+        ))?;
+        Ok(None)
+    }
+
+    pub(super) fn finish_module(&mut self, module: ObjectId) -> Result<(), HassiumError> {
+        if let HassiumObjectContext::Module { name } = &self.deref(module)?.context {
+            self.modules.insert(name.clone(), module);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::HassiumError;
+    use crate::runtime::vm::VMContext;
+
+    #[test]
+    fn compile_errors_in_modules_keep_their_kind() {
+        let dir = std::env::temp_dir().join(format!("hassium-modules-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("broken.has");
+        std::fs::write(&file, "x = 1;\ny = (2 + ;").unwrap();
+        let mut vm = VMContext::new();
+        vm.add_search_path(&dir);
+        let error = vm.run_source("x = 1;\nimport broken;", "test").unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(error, HassiumError::Syntax { .. }));
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}:2:10: SyntaxError: Unexpected Semicolon ;!\n    at <module> (test:2:1)",
+                file.display()
+            )
+        );
+    }
+}
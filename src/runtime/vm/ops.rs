@@ -0,0 +1,393 @@
+use std::ops::{BitAnd, BitOr, BitXor};
+
+use crate::error::HassiumError;
+use crate::parser::{BinOpType, UnaryOpType};
+use crate::runtime::object::defaults::{new_hassium_number, new_hassium_string, stringify};
+use crate::runtime::object::dict::{dict_get, dict_set};
+use crate::runtime::object::list::sequence_index;
+use crate::runtime::object::{HassiumObjectContext, ObjectId};
+
+use super::VMContext;
+
+/// The method a user class defines to overload a binary operator. `&&` and
+/// `||` short-circuit on truthiness and cannot be overloaded
+fn overload_name(op: &BinOpType) -> Option<&'static str> {
+    match op {
+        BinOpType::Add => Some("__add__"),
+        BinOpType::And => None,
+        BinOpType::BitwiseAnd => Some("__and__"),
+        BinOpType::BitwiseOr => Some("__or__"),
+        BinOpType::Divide => Some("__div__"),
+        BinOpType::EqualTo => Some("__eq__"),
+        BinOpType::GreaterThan => Some("__gt__"),
+        BinOpType::GreaterThanOrEqual => Some("__ge__"),
+        BinOpType::LesserThan => Some("__lt__"),
+        BinOpType::LesserThanOrEqual => Some("__le__"),
+        BinOpType::Or => None,
+        BinOpType::Modulus => Some("__mod__"),
+        BinOpType::Multiply => Some("__mul__"),
+        BinOpType::Subtract => Some("__sub__"),
+        BinOpType::Xor => Some("__xor__"),
+    }
+}
+
+impl VMContext {
+    /// Applies `op` to two operands. Instances are given the chance to overload
+    /// the operator first, `__eq__` falls back to identity when not defined
+    pub(super) fn binary_op(
+        &mut self,
+        op: &BinOpType,
+        left: ObjectId,
+        right: ObjectId,
+    ) -> Result<ObjectId, HassiumError> {
+        let is_instance = matches!(
+            self.deref(left)?.context,
+            HassiumObjectContext::Instance { .. }
+        );
+        if let (true, Some(name)) = (is_instance, overload_name(op)) {
+            if self.get_attrib(left, name)?.is_some() {
+                return self.call_attrib(left, name, vec![right]);
+            }
+        }
+
+        match op {
+            BinOpType::And => {
+                let value = self.is_truthy(left)? && self.is_truthy(right)?;
+                return Ok(self.bool(value));
+            }
+            BinOpType::Or => {
+                let value = self.is_truthy(left)? || self.is_truthy(right)?;
+                return Ok(self.bool(value));
+            }
+            BinOpType::EqualTo => {
+                let value = self.values_equal(left, right)?;
+                return Ok(self.bool(value));
+            }
+            _ => (),
+        }
+
+        // The operands are borrowed rather than copied so that lists and dicts
+        // aren't cloned just to find out they're unsupported
+        let left_context = &self.deref(left)?.context;
+        let right_context = &self.deref(right)?.context;
+        match (left_context, right_context) {
+            (HassiumObjectContext::Number(a), HassiumObjectContext::Number(b)) => {
+                let (a, b) = (*a, *b);
+                self.number_op(op, a, b)
+            }
+            (HassiumObjectContext::String(_), _) | (_, HassiumObjectContext::String(_))
+                if matches!(op, BinOpType::Add) =>
+            {
+                let value = stringify(self, left)? + &stringify(self, right)?;
+                Ok(new_hassium_string(self, value))
+            }
+            (HassiumObjectContext::String(a), HassiumObjectContext::String(b)) => {
+                let value = match op {
+                    BinOpType::GreaterThan => a > b,
+                    BinOpType::GreaterThanOrEqual => a >= b,
+                    BinOpType::LesserThan => a < b,
+                    BinOpType::LesserThanOrEqual => a <= b,
+                    _ => return Err(unsupported(op, left_context, right_context)),
+                };
+                Ok(self.bool(value))
+            }
+            (HassiumObjectContext::Bool(a), HassiumObjectContext::Bool(b)) => {
+                let value = match op {
+                    BinOpType::BitwiseAnd => a & b,
+                    BinOpType::BitwiseOr => a | b,
+                    BinOpType::Xor => a ^ b,
+                    _ => return Err(unsupported(op, left_context, right_context)),
+                };
+                Ok(self.bool(value))
+            }
+            _ => Err(unsupported(op, left_context, right_context)),
+        }
+    }
+
+    fn number_op(&mut self, op: &BinOpType, a: f64, b: f64) -> Result<ObjectId, HassiumError> {
+        let value = match op {
+            BinOpType::Add => a + b,
+            BinOpType::BitwiseAnd | BinOpType::BitwiseOr | BinOpType::Xor => bitwise_op(op, a, b)?,
+            BinOpType::Divide | BinOpType::Modulus if b == 0.0 => {
+                return Err(HassiumError::runtime_error("Division by zero!".to_string()))
+            }
+            BinOpType::Divide => a / b,
+            BinOpType::Modulus => a % b,
+            BinOpType::Multiply => a * b,
+            BinOpType::Subtract => a - b,
+            _ => {
+                let value = match op {
+                    BinOpType::GreaterThan => a > b,
+                    BinOpType::GreaterThanOrEqual => a >= b,
+                    BinOpType::LesserThan => a < b,
+                    _ => a <= b,
+                };
+                return Ok(self.bool(value));
+            }
+        };
+        Ok(new_hassium_number(self, value))
+    }
+
+    /// Equality used by `==`. Built-in values compare by value, lists and tuples
+    /// item by item, dicts entry by entry and anything else by identity
+    pub fn values_equal(&mut self, left: ObjectId, right: ObjectId) -> Result<bool, HassiumError> {
+        if left == right {
+            return Ok(true);
+        }
+        let context = &self.deref(left)?.context;
+        let is_instance = matches!(context, HassiumObjectContext::Instance { .. });
+        let is_container = matches!(
+            context,
+            HassiumObjectContext::Dict(_)
+                | HassiumObjectContext::List(_)
+                | HassiumObjectContext::Tuple(_)
+        );
+        if is_instance && self.get_attrib(left, "__eq__")?.is_some() {
+            let value = self.call_attrib(left, "__eq__", vec![right])?;
+            return self.is_truthy(value);
+        }
+        // Containers that hold themselves lead back to a pair that is already
+        // being compared, nothing that tells them apart was found on the way
+        if !is_container {
+            return self.contents_equal(left, right);
+        }
+        if self.comparing.contains(&(left, right)) {
+            return Ok(true);
+        }
+        self.comparing.push((left, right));
+        let ret = self.contents_equal(left, right);
+        self.comparing.pop();
+        ret
+    }
+
+    fn contents_equal(&mut self, left: ObjectId, right: ObjectId) -> Result<bool, HassiumError> {
+        Ok(
+            match (&self.deref(left)?.context, &self.deref(right)?.context) {
+                (HassiumObjectContext::Bool(a), HassiumObjectContext::Bool(b)) => a == b,
+                (HassiumObjectContext::None, HassiumObjectContext::None) => true,
+                (HassiumObjectContext::Number(a), HassiumObjectContext::Number(b)) => a == b,
+                (HassiumObjectContext::String(a), HassiumObjectContext::String(b)) => a == b,
+                (HassiumObjectContext::List(a), HassiumObjectContext::List(b))
+                | (HassiumObjectContext::Tuple(a), HassiumObjectContext::Tuple(b)) => {
+                    if a.len() != b.len() {
+                        return Ok(false);
+                    }
+                    for (a, b) in a.clone().into_iter().zip(b.clone()) {
+                        if !self.values_equal(a, b)? {
+                            return Ok(false);
+                        }
+                    }
+                    true
+                }
+                (HassiumObjectContext::Dict(a), HassiumObjectContext::Dict(b)) => {
+                    if a.len() != b.len() {
+                        return Ok(false);
+                    }
+                    for entry in a.clone().iter() {
+                        let other = match dict_get(self, right, entry.key)? {
+                            Some(other) => other,
+                            None => return Ok(false),
+                        };
+                        if !self.values_equal(entry.value, other)? {
+                            return Ok(false);
+                        }
+                    }
+                    true
+                }
+                _ => false,
+            },
+        )
+    }
+
+    /// Whether `left < right`, used to order values when sorting
+    pub fn less_than(&mut self, left: ObjectId, right: ObjectId) -> Result<bool, HassiumError> {
+        let value = self.binary_op(&BinOpType::LesserThan, left, right)?;
+        self.is_truthy(value)
+    }
+
+    /// Reads `target[key]`. Lists, tuples and strings are indexed by number,
+    /// dicts by key
+    pub(super) fn load_subscript(
+        &mut self,
+        target: ObjectId,
+        key: ObjectId,
+    ) -> Result<ObjectId, HassiumError> {
+        let len = match &self.deref(target)?.context {
+            HassiumObjectContext::Dict(_) => {
+                return match dict_get(self, target, key)? {
+                    Some(value) => Ok(value),
+                    None => Err(HassiumError::runtime_error(format!(
+                        "Key {} not found!",
+                        stringify(self, key)?
+                    ))),
+                }
+            }
+            HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items) => items.len(),
+            HassiumObjectContext::String(string) => {
+                let chars: Vec<char> = string.chars().collect();
+                let index = sequence_index(self, key, chars.len())?;
+                return Ok(new_hassium_string(self, chars[index].to_string()));
+            }
+            context => {
+                return Err(HassiumError::type_error(format!(
+                    "Object of type {} is not subscriptable!",
+                    context.type_name()
+                )))
+            }
+        };
+        let index = sequence_index(self, key, len)?;
+        match &self.deref(target)?.context {
+            HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items) => {
+                Ok(items[index])
+            }
+            context => Err(HassiumError::type_error(format!(
+                "Object of type {} is not subscriptable!",
+                context.type_name()
+            ))),
+        }
+    }
+
+    /// Performs `target[key] = value`, lists and dicts can be assigned into
+    pub(super) fn store_subscript(
+        &mut self,
+        target: ObjectId,
+        key: ObjectId,
+        value: ObjectId,
+    ) -> Result<(), HassiumError> {
+        let len = match &self.deref(target)?.context {
+            HassiumObjectContext::Dict(_) => return dict_set(self, target, key, value),
+            HassiumObjectContext::List(items) => items.len(),
+            context => {
+                return Err(HassiumError::type_error(format!(
+                    "Object of type {} does not support subscript assignment!",
+                    context.type_name()
+                )))
+            }
+        };
+        let index = sequence_index(self, key, len)?;
+        if let Some(HassiumObjectContext::List(items)) =
+            self.heap.get_mut(target).map(|obj| &mut obj.context)
+        {
+            items[index] = value;
+        }
+        Ok(())
+    }
+
+    /// The items of a tuple or list being destructured into `count` names
+    pub(super) fn unpack(
+        &mut self,
+        value: ObjectId,
+        count: usize,
+    ) -> Result<Vec<ObjectId>, HassiumError> {
+        match &self.deref(value)?.context {
+            HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items)
+                if items.len() == count =>
+            {
+                Ok(items.clone())
+            }
+            HassiumObjectContext::List(items) | HassiumObjectContext::Tuple(items) => {
+                Err(HassiumError::runtime_error(format!(
+                    "Cannot unpack {} values into {} names!",
+                    items.len(),
+                    count
+                )))
+            }
+            context => Err(HassiumError::type_error(format!(
+                "Cannot unpack a {}!",
+                context.type_name()
+            ))),
+        }
+    }
+
+    pub(super) fn unary_op(
+        &mut self,
+        op: &UnaryOpType,
+        target: ObjectId,
+    ) -> Result<ObjectId, HassiumError> {
+        match op {
+            UnaryOpType::Not => {
+                let value = !self.is_truthy(target)?;
+                Ok(self.bool(value))
+            }
+            // Going through Add and Subtract lets classes overloading those
+            // support ++ and -- as well
+            UnaryOpType::DecrementPre => {
+                let one = new_hassium_number(self, 1.0);
+                self.binary_op(&BinOpType::Subtract, target, one)
+            }
+            UnaryOpType::IncrementPre => {
+                let one = new_hassium_number(self, 1.0);
+                self.binary_op(&BinOpType::Add, target, one)
+            }
+            UnaryOpType::Negate => {
+                let context = &self.deref(target)?.context;
+                if let HassiumObjectContext::Number(value) = context {
+                    let value = -*value;
+                    return Ok(new_hassium_number(self, value));
+                }
+                let type_name = context.type_name();
+                if matches!(context, HassiumObjectContext::Instance { .. })
+                    && self.get_attrib(target, "__neg__")?.is_some()
+                {
+                    return self.call_attrib(target, "__neg__", Vec::new());
+                }
+                Err(HassiumError::type_error(format!(
+                    "Unsupported operand type for Negate: {}!",
+                    type_name
+                )))
+            }
+        }
+    }
+}
+
+fn unsupported(
+    op: &BinOpType,
+    left: &HassiumObjectContext,
+    right: &HassiumObjectContext,
+) -> HassiumError {
+    HassiumError::type_error(format!(
+        "Unsupported operand types for {:?}: {} and {}!",
+        op,
+        left.type_name(),
+        right.type_name()
+    ))
+}
+
+/// Checks that `value` is a whole number in `min..max`, bitwise operators work
+/// on 64 bit integers and reject numbers that would have to be truncated or
+/// saturated to become one
+fn bitwise_operand(op: &BinOpType, value: f64, min: f64, max: f64) -> Result<f64, HassiumError> {
+    if value.fract() != 0.0 || value < min || value >= max {
+        return Err(HassiumError::runtime_error(format!(
+            "{:?} needs whole numbers that fit in 64 bits, got {}!",
+            op, value
+        )));
+    }
+    Ok(value)
+}
+
+/// Applies a bitwise operator to two numbers, as u64 unless one is negative and
+/// as i64 otherwise
+fn bitwise_op(op: &BinOpType, a: f64, b: f64) -> Result<f64, HassiumError> {
+    const TWO_POW_63: f64 = 9_223_372_036_854_775_808.0;
+    const TWO_POW_64: f64 = 18_446_744_073_709_551_616.0;
+    fn apply<T>(op: &BinOpType, a: T, b: T) -> T
+    where
+        T: BitAnd<Output = T> + BitOr<Output = T> + BitXor<Output = T>,
+    {
+        match op {
+            BinOpType::BitwiseAnd => a & b,
+            BinOpType::BitwiseOr => a | b,
+            _ => a ^ b,
+        }
+    }
+    Ok(if a < 0.0 || b < 0.0 {
+        let a = bitwise_operand(op, a, -TWO_POW_63, TWO_POW_63)? as i64;
+        let b = bitwise_operand(op, b, -TWO_POW_63, TWO_POW_63)? as i64;
+        apply(op, a, b) as f64
+    } else {
+        let a = bitwise_operand(op, a, 0.0, TWO_POW_64)? as u64;
+        let b = bitwise_operand(op, b, 0.0, TWO_POW_64)? as u64;
+        apply(op, a, b) as f64
+    })
+}
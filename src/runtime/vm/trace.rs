@@ -0,0 +1,116 @@
+use crate::error::HassiumError;
+use crate::runtime::object::{HassiumObject, ObjectId};
+
+use super::VMInstruction;
+
+/// Receives execution events from a VM, for debug logs, coverage and profilers.
+/// Every callback does nothing by default so implementors only override the
+/// events they care about
+pub trait Tracer {
+    /// Called before the instruction at `pos` in the code running as `frame` executes
+    fn on_instruction(&mut self, _frame: &str, _pos: u32, _inst: &VMInstruction) {}
+
+    /// Called for each object as it is added to the heap
+    fn on_alloc(&mut self, _obj: &HassiumObject) {}
+
+    /// Called when a function, constructor or class body starts running.
+    /// `depth` is the number of frames below it
+    fn on_call(&mut self, _name: &str, _depth: usize) {}
+
+    /// Called when a frame is left. `value` is None when the frame returned no
+    /// value to its caller or was unwound by an exception
+    fn on_return(&mut self, _name: &str, _depth: usize, _value: Option<ObjectId>) {}
+
+    /// Called when an exception is raised, before any handler runs
+    fn on_exception(&mut self, _exception: ObjectId, _error: &HassiumError) {}
+}
+
+/// Writes every event to stderr, indented by call depth
+pub struct PrintTracer;
+
+impl Tracer for PrintTracer {
+    fn on_instruction(&mut self, frame: &str, pos: u32, inst: &VMInstruction) {
+        eprintln!("[{}:{}] {}", frame, pos, inst);
+    }
+
+    fn on_alloc(&mut self, obj: &HassiumObject) {
+        eprintln!("alloc {}", obj.id);
+    }
+
+    fn on_call(&mut self, name: &str, depth: usize) {
+        eprintln!("{}-> {}", "  ".repeat(depth), name);
+    }
+
+    fn on_return(&mut self, name: &str, depth: usize, value: Option<ObjectId>) {
+        match value {
+            Some(value) => eprintln!("{}<- {} = {}", "  ".repeat(depth), name, value),
+            None => eprintln!("{}<- {}", "  ".repeat(depth), name),
+        }
+    }
+
+    fn on_exception(&mut self, exception: ObjectId, error: &HassiumError) {
+        eprintln!("raise {}: {}", exception, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::runtime::vm::VMContext;
+
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Tracer for Recorder {
+        fn on_call(&mut self, name: &str, depth: usize) {
+            self.0.borrow_mut().push(format!("call {} {}", name, depth));
+        }
+
+        fn on_return(&mut self, name: &str, depth: usize, value: Option<ObjectId>) {
+            let value = if value.is_some() { "value" } else { "none" };
+            self.0
+                .borrow_mut()
+                .push(format!("return {} {} {}", name, depth, value));
+        }
+
+        fn on_exception(&mut self, _exception: ObjectId, error: &HassiumError) {
+            self.0
+                .borrow_mut()
+                .push(format!("raise {}", error.message()));
+        }
+    }
+
+    #[test]
+    fn calls_returns_and_exceptions_are_traced_in_order() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VMContext::new();
+        vm.set_tracer(Box::new(Recorder(Rc::clone(&events))));
+        vm.run_source(
+            "func inner() { raise \"oops\"; }
+            func outer() {
+                try { inner(); } catch (e) {}
+                return 1;
+            }
+            outer();",
+            "test",
+        )
+        .unwrap();
+        assert_eq!(
+            *events.borrow(),
+            [
+                "call <module> 0",
+                "call outer 1",
+                "call inner 2",
+                // Turning the raised value into an error reads its message
+                "call toString 3",
+                "return toString 3 value",
+                "raise oops",
+                "return inner 2 none",
+                "return outer 1 value",
+                "return <module> 0 value",
+            ]
+        );
+    }
+}